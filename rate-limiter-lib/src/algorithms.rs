@@ -0,0 +1,934 @@
+use crate::{LimitType, StoredValue};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Deserialize;
+
+/// What a caller asked for on a single check. `ttl` is the window length in seconds and `cost` the
+/// number of units the request consumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub limit: LimitType,
+    pub ttl: i64,
+    pub cost: LimitType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    /// Rejected, the caller should wait `retry_after` seconds before trying again
+    Deny { retry_after: i64 },
+    /// Rejected and the state has no expiry to wait for, retrying won't help until it is reset
+    Exhausted,
+}
+
+/// Algorithm neutral view of a key's state used to move it from one algorithm to another: the units
+/// of the limit in use and when the key expires, None for keys that never do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub used: LimitType,
+    pub reset_at: Option<DateTime<Utc>>,
+}
+
+/// A rate limiting algorithm. Implementations are pure, all the state they need lives in the
+/// StoredValue they are handed and time is passed in, so the Store owns persistence and locking
+/// while each algorithm can be tested on its own.
+pub trait Algorithm: Send + Sync {
+    /// State for a key that is not tracked yet. It is only persisted if the first check allows the
+    /// request so denied callers never leave a bucket behind.
+    fn initial(&self, now: DateTime<Utc>, params: &Params) -> StoredValue;
+
+    /// Decide whether the request fits and update the state to account for it. The state must be
+    /// left untouched on a denial.
+    fn check(&self, state: &mut StoredValue, now: DateTime<Utc>, params: &Params) -> Decision;
+
+    /// How much of the limit the state has in use at `now`, see Store::migrate
+    fn usage(&self, state: &StoredValue, now: DateTime<Utc>, params: &Params) -> Usage;
+
+    /// State that has the usage's units of the limit in use at `now`, keeping its reset time where
+    /// the algorithm allows it. Only called with some units in use.
+    fn with_usage(&self, usage: Usage, now: DateTime<Utc>, params: &Params) -> StoredValue;
+}
+
+/// The original counter model. A window starts with the first request and the counter only resets
+/// once the main loop evicts the key at the end of the window. A key without a ttl never resets, so
+/// once it is full it is Decision::Exhausted rather than told to wait.
+pub struct FixedWindow;
+
+impl Algorithm for FixedWindow {
+    fn initial(&self, now: DateTime<Utc>, params: &Params) -> StoredValue {
+        StoredValue {
+            count: 0,
+            ttl: Some(now + Duration::seconds(params.ttl)),
+            ..Default::default()
+        }
+    }
+
+    fn check(&self, state: &mut StoredValue, now: DateTime<Utc>, params: &Params) -> Decision {
+        // a count inserted or charged by inc_by may be close enough to MAX that adding would wrap
+        if let Some(count) = state.count.checked_add(params.cost).filter(|count| *count <= params.limit) {
+            state.count = count;
+            return Decision::Allow;
+        }
+        match state.ttl {
+            // rounded up so a caller is never told to retry while part of a second is left
+            Some(ttl) => Decision::Deny {
+                retry_after: (ttl.signed_duration_since(now).num_milliseconds() + 999) / 1000,
+            },
+            None => Decision::Exhausted,
+        }
+    }
+
+    fn usage(&self, state: &StoredValue, now: DateTime<Utc>, _params: &Params) -> Usage {
+        Usage {
+            used: if state.ttl.is_some_and(|ttl| ttl <= now) { 0 } else { state.count },
+            reset_at: state.ttl,
+        }
+    }
+
+    fn with_usage(&self, usage: Usage, _now: DateTime<Utc>, _params: &Params) -> StoredValue {
+        StoredValue {
+            count: usage.used,
+            ttl: usage.reset_at,
+            ..Default::default()
+        }
+    }
+}
+
+/// Like the fixed window, except each allowed request pushes the expiry out to `ttl` seconds from
+/// now, so the count only resets once the key has been idle for a whole window. Denied requests
+/// leave the expiry alone, otherwise a caller hammering a limited key would never be let back in.
+pub struct RefreshingWindow;
+
+impl Algorithm for RefreshingWindow {
+    fn initial(&self, now: DateTime<Utc>, params: &Params) -> StoredValue {
+        FixedWindow.initial(now, params)
+    }
+
+    fn check(&self, state: &mut StoredValue, now: DateTime<Utc>, params: &Params) -> Decision {
+        let decision = FixedWindow.check(state, now, params);
+        if decision == Decision::Allow {
+            state.ttl = Some(now + Duration::seconds(params.ttl));
+        }
+        decision
+    }
+
+    fn usage(&self, state: &StoredValue, now: DateTime<Utc>, params: &Params) -> Usage {
+        FixedWindow.usage(state, now, params)
+    }
+
+    fn with_usage(&self, usage: Usage, now: DateTime<Utc>, params: &Params) -> StoredValue {
+        FixedWindow.with_usage(usage, now, params)
+    }
+}
+
+/// Counts the requests made in the last `ttl` seconds rather than since the window started, so a
+/// burst at the end of one window can't be followed by a full burst at the start of the next. Hits
+/// are kept in per-second buckets (unix second, units) oldest first, which bounds the state to one
+/// bucket per second of the window. A bucket is only dropped once the whole second it covers has
+/// left the window, so the limit may be held slightly longer than the window but never exceeded.
+pub struct SlidingWindow;
+
+impl SlidingWindow {
+    /// The time at which the hits in the bucket for `second` have all left a `ttl` second window
+    fn bucket_expiry(second: i64, ttl: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(second + 1 + ttl, 0).single().unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// Add `units` hits at `now` to the current second's bucket without checking them against any
+    /// limit, dropping the buckets that have left the window. The count and expiry are rebuilt from
+    /// the buckets so they never disagree with what check sees, whether the hits came through check
+    /// or were charged after the fact by Store::inc_by.
+    pub fn record(state: &mut StoredValue, now: DateTime<Utc>, ttl: i64, units: LimitType) {
+        let second = now.timestamp();
+        state.buckets.retain(|(bucket, _)| Self::bucket_expiry(*bucket, ttl) > now);
+        match state.buckets.last_mut() {
+            Some((last, bucket_units)) if *last == second => *bucket_units += units,
+            _ => state.buckets.push((second, units)),
+        }
+        state.count = state.buckets.iter().map(|(_, units)| units).sum();
+        // the key is of no use once its newest hit has left the window, let the main loop drop it
+        state.ttl = Some(Self::bucket_expiry(second, ttl));
+    }
+}
+
+impl Algorithm for SlidingWindow {
+    fn initial(&self, now: DateTime<Utc>, params: &Params) -> StoredValue {
+        StoredValue {
+            count: 0,
+            ttl: Some(now + Duration::seconds(params.ttl)),
+            ..Default::default()
+        }
+    }
+
+    fn check(&self, state: &mut StoredValue, now: DateTime<Utc>, params: &Params) -> Decision {
+        let live: Vec<(i64, LimitType)> = state
+            .buckets
+            .iter()
+            .copied()
+            .filter(|(second, _)| Self::bucket_expiry(*second, params.ttl) > now)
+            .collect();
+        let count = live.iter().fold(0 as LimitType, |count, (_, units)| count.saturating_add(*units));
+        if count.saturating_add(params.cost) > params.limit {
+            // wait for just enough of the oldest buckets to leave the window
+            let mut freed = 0;
+            let retry_at = live.iter().find_map(|(second, units)| {
+                freed += units;
+                ((count - freed).saturating_add(params.cost) <= params.limit).then(|| Self::bucket_expiry(*second, params.ttl))
+            });
+            let retry_after = retry_at
+                .map(|at| (at.signed_duration_since(now).num_milliseconds() + 999) / 1000)
+                .unwrap_or(params.ttl);
+            return Decision::Deny { retry_after };
+        }
+        Self::record(state, now, params.ttl, params.cost);
+        Decision::Allow
+    }
+
+    fn usage(&self, state: &StoredValue, now: DateTime<Utc>, params: &Params) -> Usage {
+        Usage {
+            used: state
+                .buckets
+                .iter()
+                .filter(|(second, _)| Self::bucket_expiry(*second, params.ttl) > now)
+                .map(|(_, units)| units)
+                .sum(),
+            reset_at: state.ttl,
+        }
+    }
+
+    /// Every unit goes into the one bucket that leaves the window at the reset time, or into the
+    /// current second's when that would be in the future
+    fn with_usage(&self, usage: Usage, now: DateTime<Utc>, params: &Params) -> StoredValue {
+        let second = usage
+            .reset_at
+            .map(|reset_at| reset_at.timestamp() - 1 - params.ttl)
+            .unwrap_or(i64::MAX)
+            .min(now.timestamp());
+        let mut state = StoredValue {
+            buckets: vec![(second, usage.used)],
+            count: usage.used,
+            ttl: Some(Self::bucket_expiry(second, params.ttl)),
+            ..Default::default()
+        };
+        if state.ttl.is_some_and(|ttl| ttl <= now) {
+            state = self.initial(now, params);
+        }
+        state
+    }
+}
+
+/// Lets callers burst up to `limit` tokens and then holds them to a steady `refill_per_sec`. The
+/// bucket is refilled lazily from the time elapsed since the last refill, capped at the limit so a
+/// long idle period never banks more than one full burst. A key only needs to be kept until its
+/// bucket is full again, an absent key starts full.
+pub struct TokenBucket {
+    pub refill_per_sec: f64,
+}
+
+impl TokenBucket {
+    /// Whole seconds it takes to refill `tokens`
+    pub fn seconds_until(&self, tokens: f64) -> i64 {
+        // float to int casts saturate so a tiny or zero refill rate can't overflow
+        (tokens / self.refill_per_sec).ceil() as i64
+    }
+
+    fn refilled(&self, state: &StoredValue, now: DateTime<Utc>, params: &Params) -> f64 {
+        let elapsed = state
+            .last_refill
+            .map(|last_refill| now.signed_duration_since(last_refill).num_milliseconds().max(0))
+            .map(|elapsed_ms| elapsed_ms as f64 / 1000.0)
+            .unwrap_or_default();
+        (state.tokens + elapsed * self.refill_per_sec).min(params.limit as f64)
+    }
+}
+
+impl Algorithm for TokenBucket {
+    fn initial(&self, now: DateTime<Utc>, params: &Params) -> StoredValue {
+        StoredValue {
+            tokens: params.limit as f64,
+            last_refill: Some(now),
+            ..Default::default()
+        }
+    }
+
+    fn check(&self, state: &mut StoredValue, now: DateTime<Utc>, params: &Params) -> Decision {
+        let tokens = self.refilled(state, now, params);
+        let cost = params.cost as f64;
+        if tokens < cost {
+            return Decision::Deny {
+                retry_after: self.seconds_until(cost - tokens),
+            };
+        }
+        state.tokens = tokens - cost;
+        state.last_refill = Some(now);
+        state.count = (params.limit as f64 - state.tokens).ceil() as LimitType;
+        let until_full = self.seconds_until(params.limit as f64 - state.tokens).saturating_mul(1000);
+        state.ttl = Some(
+            now.checked_add_signed(Duration::milliseconds(until_full))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        );
+        Decision::Allow
+    }
+
+    fn usage(&self, state: &StoredValue, now: DateTime<Utc>, params: &Params) -> Usage {
+        Usage {
+            used: (params.limit as f64 - self.refilled(state, now, params)).ceil().max(0.0) as LimitType,
+            reset_at: state.ttl,
+        }
+    }
+
+    /// The bucket refills at its own rate, so the reset time follows from the tokens that are used
+    fn with_usage(&self, usage: Usage, now: DateTime<Utc>, params: &Params) -> StoredValue {
+        let tokens = (params.limit - usage.used).max(0) as f64;
+        let until_full = self.seconds_until(params.limit as f64 - tokens).saturating_mul(1000);
+        StoredValue {
+            count: usage.used.min(params.limit),
+            tokens,
+            last_refill: Some(now),
+            ttl: Some(
+                now.checked_add_signed(Duration::milliseconds(until_full))
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+/// Traffic shaping bucket that fills by each request's cost and drains at a constant
+/// `leak_per_sec`, requests that would overflow the `limit` are rejected. The level is drained
+/// lazily from the time elapsed since the last update and never drops below empty, so a long idle
+/// gap leaves room for at most one full bucket. A key only needs to be kept until it has drained.
+pub struct LeakyBucket {
+    pub leak_per_sec: f64,
+}
+
+impl LeakyBucket {
+    /// Whole seconds it takes to drain `level`
+    pub fn seconds_until(&self, level: f64) -> i64 {
+        // float to int casts saturate so a tiny or zero leak rate can't overflow
+        (level / self.leak_per_sec).ceil() as i64
+    }
+
+    fn drained(&self, state: &StoredValue, now: DateTime<Utc>) -> f64 {
+        let elapsed = state
+            .last_leak
+            .map(|last_leak| now.signed_duration_since(last_leak).num_milliseconds().max(0))
+            .map(|elapsed_ms| elapsed_ms as f64 / 1000.0)
+            .unwrap_or_default();
+        (state.level - elapsed * self.leak_per_sec).max(0.0)
+    }
+}
+
+impl Algorithm for LeakyBucket {
+    fn initial(&self, now: DateTime<Utc>, _params: &Params) -> StoredValue {
+        StoredValue {
+            last_leak: Some(now),
+            ..Default::default()
+        }
+    }
+
+    fn check(&self, state: &mut StoredValue, now: DateTime<Utc>, params: &Params) -> Decision {
+        let level = self.drained(state, now) + params.cost as f64;
+        let overflow = level - params.limit as f64;
+        if overflow > 0.0 {
+            return Decision::Deny {
+                retry_after: self.seconds_until(overflow),
+            };
+        }
+        state.level = level;
+        state.last_leak = Some(now);
+        state.count = level.ceil() as LimitType;
+        let until_empty = self.seconds_until(level).saturating_mul(1000);
+        state.ttl = Some(
+            now.checked_add_signed(Duration::milliseconds(until_empty))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        );
+        Decision::Allow
+    }
+
+    fn usage(&self, state: &StoredValue, now: DateTime<Utc>, _params: &Params) -> Usage {
+        Usage {
+            used: self.drained(state, now).ceil() as LimitType,
+            reset_at: state.ttl,
+        }
+    }
+
+    /// The bucket drains at its own rate, so the reset time follows from the level
+    fn with_usage(&self, usage: Usage, now: DateTime<Utc>, _params: &Params) -> StoredValue {
+        let level = usage.used as f64;
+        let until_empty = self.seconds_until(level).saturating_mul(1000);
+        StoredValue {
+            count: usage.used,
+            level,
+            last_leak: Some(now),
+            ttl: Some(
+                now.checked_add_signed(Duration::milliseconds(until_empty))
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+/// Generic cell rate algorithm. Requests are paced one `emission_interval` apart by tracking the
+/// theoretical arrival time (tat) of the next conforming request, and up to `limit` requests may
+/// arrive ahead of it as a burst. Only allowed requests move the tat forward, and the key expires
+/// once the tat has passed since the caller has its whole burst back by then.
+pub struct Gcra {
+    pub emission_interval: Duration,
+}
+
+impl Gcra {
+    /// Pace requests `period_secs` apart, kept to millisecond precision
+    pub fn every(period_secs: f64) -> Self {
+        Gcra {
+            emission_interval: Duration::milliseconds((period_secs * 1000.0).round() as i64),
+        }
+    }
+
+    /// Requests that have arrived ahead of their emission time as of `now`, rounded up
+    fn requests_ahead(&self, tat: DateTime<Utc>, now: DateTime<Utc>) -> LimitType {
+        let interval_ms = self.emission_interval.num_milliseconds().max(1);
+        let ahead_ms = tat.signed_duration_since(now).num_milliseconds().max(0);
+        (ahead_ms + interval_ms - 1) / interval_ms
+    }
+}
+
+impl Algorithm for Gcra {
+    fn initial(&self, _now: DateTime<Utc>, _params: &Params) -> StoredValue {
+        StoredValue::default()
+    }
+
+    fn check(&self, state: &mut StoredValue, now: DateTime<Utc>, params: &Params) -> Decision {
+        let interval_ms = self.emission_interval.num_milliseconds();
+        // saturate rather than wrap on absurd costs or limits
+        let increment = Duration::milliseconds(interval_ms.saturating_mul(params.cost));
+        let burst = Duration::milliseconds(interval_ms.saturating_mul(params.limit));
+        let tat = state
+            .tat
+            .unwrap_or(now)
+            .max(now)
+            .checked_add_signed(increment)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        let allow_at = tat.checked_sub_signed(burst).unwrap_or(DateTime::<Utc>::MIN_UTC);
+        if now < allow_at {
+            let wait_ms = allow_at.signed_duration_since(now).num_milliseconds();
+            return Decision::Deny {
+                retry_after: (wait_ms + 999) / 1000,
+            };
+        }
+        state.tat = Some(tat);
+        state.ttl = Some(tat);
+        state.count = self.requests_ahead(tat, now);
+        Decision::Allow
+    }
+
+    fn usage(&self, state: &StoredValue, now: DateTime<Utc>, _params: &Params) -> Usage {
+        Usage {
+            used: state.tat.map(|tat| self.requests_ahead(tat, now)).unwrap_or_default(),
+            reset_at: state.tat,
+        }
+    }
+
+    /// Requests are paced at the emission interval, so the tat follows from the requests in use
+    fn with_usage(&self, usage: Usage, now: DateTime<Utc>, _params: &Params) -> StoredValue {
+        let ahead_ms = self.emission_interval.num_milliseconds().saturating_mul(usage.used);
+        let tat = now
+            .checked_add_signed(Duration::milliseconds(ahead_ms))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        StoredValue {
+            count: usage.used,
+            tat: Some(tat),
+            ttl: Some(tat),
+            ..Default::default()
+        }
+    }
+}
+
+/// Which window a key is limited over
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowMode {
+    /// See FixedWindow
+    #[default]
+    Fixed,
+    /// See SlidingWindow
+    Sliding,
+    /// See RefreshingWindow
+    RefreshOnHit,
+}
+
+impl WindowMode {
+    pub fn algorithm(self) -> &'static dyn Algorithm {
+        match self {
+            WindowMode::Fixed => &FixedWindow,
+            WindowMode::Sliding => &SlidingWindow,
+            WindowMode::RefreshOnHit => &RefreshingWindow,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod harness {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// One simulated request, `at_ms` milliseconds after the simulation started
+    #[derive(Debug, Clone, Copy)]
+    pub struct Request {
+        pub at_ms: i64,
+        pub cost: LimitType,
+    }
+
+    /// Random parameter sets, each with a burst-heavy request sequence whose costs stay within the
+    /// parameters' cost
+    pub fn cases() -> impl Strategy<Value = (Params, Vec<Request>)> {
+        (1..=20 as LimitType, 1..=10_i64, 1..=3 as LimitType).prop_flat_map(|(limit, ttl, cost)| {
+            // mostly bursts with the odd long pause to cross window boundaries
+            let gap = prop_oneof![9 => 0..=200_i64, 1 => 0..=15_000_i64];
+            let requests = prop::collection::vec((gap, 1..=cost), 1..=200).prop_map(|steps| {
+                let mut at_ms = 0;
+                steps
+                    .into_iter()
+                    .map(|(gap, cost)| {
+                        at_ms += gap;
+                        Request { at_ms, cost }
+                    })
+                    .collect()
+            });
+            (Just(Params { limit, ttl, cost }), requests)
+        })
+    }
+
+    /// Run the requests through the algorithm the same way the Store does, evicting the state once
+    /// its ttl has passed like the main loop, and return the requests that were allowed.
+    pub fn admitted(algorithm: &dyn Algorithm, params: &Params, requests: &[Request]) -> Vec<Request> {
+        // start on a whole second so bucketed algorithms see the same boundaries on every run
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut state: Option<StoredValue> = None;
+        let mut admitted = Vec::new();
+        for request in requests {
+            let now = start + Duration::milliseconds(request.at_ms);
+            if state.as_ref().and_then(|state| state.ttl).map(|ttl| now > ttl).unwrap_or_default() {
+                state = None;
+            }
+            let mut next = state.clone().unwrap_or_else(|| algorithm.initial(now, params));
+            let params = Params {
+                cost: request.cost,
+                ..*params
+            };
+            if algorithm.check(&mut next, now, &params) == Decision::Allow {
+                state = Some(next);
+                admitted.push(*request);
+            }
+        }
+        admitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{harness::*, *};
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn fixed_window_never_admits_more_than_limit_per_window((params, requests) in cases()) {
+            let admitted = admitted(&FixedWindow, &params, &requests);
+            // windows start at the first admitted request after the previous one ended
+            let mut window_end = i64::MIN;
+            let mut in_window = 0;
+            for request in admitted {
+                if request.at_ms > window_end {
+                    window_end = request.at_ms + params.ttl * 1000;
+                    in_window = 0;
+                }
+                in_window += request.cost;
+                prop_assert!(in_window <= params.limit, "admitted {} in one window", in_window);
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_window_denial_leaves_state_untouched() {
+        let now = Utc::now();
+        let params = Params {
+            limit: 2,
+            ttl: 30,
+            cost: 2,
+        };
+        let mut state = FixedWindow.initial(now, &params);
+        assert_eq!(FixedWindow.check(&mut state, now, &params), Decision::Allow);
+        let before = state.clone();
+        let later = now + Duration::seconds(10);
+        assert_eq!(FixedWindow.check(&mut state, later, &params), Decision::Deny { retry_after: 20 });
+        assert!(state == before);
+    }
+
+    #[test]
+    fn fixed_window_rounds_a_partial_second_up() {
+        let now = Utc::now();
+        let params = Params {
+            limit: 1,
+            ttl: 30,
+            cost: 1,
+        };
+        let mut state = FixedWindow.initial(now, &params);
+        assert_eq!(FixedWindow.check(&mut state, now, &params), Decision::Allow);
+        let almost = now + Duration::milliseconds(29_700);
+        assert_eq!(FixedWindow.check(&mut state, almost, &params), Decision::Deny { retry_after: 1 });
+        let mid = now + Duration::milliseconds(10_500);
+        assert_eq!(FixedWindow.check(&mut state, mid, &params), Decision::Deny { retry_after: 20 });
+    }
+
+    #[test]
+    fn fixed_window_without_ttl_is_exhausted() {
+        let now = Utc::now();
+        let params = Params {
+            limit: 1,
+            ttl: 30,
+            cost: 1,
+        };
+        let mut state = StoredValue {
+            count: 1,
+            ..Default::default()
+        };
+        assert_eq!(FixedWindow.check(&mut state, now, &params), Decision::Exhausted);
+        assert_eq!(state.count, 1);
+    }
+
+    proptest! {
+        #[test]
+        fn refreshing_window_never_admits_more_than_limit_until_idle_for_a_window((params, requests) in cases()) {
+            let admitted = admitted(&RefreshingWindow, &params, &requests);
+            // the count only resets once a whole window passes without an admitted request
+            let mut last_admitted_ms = i64::MIN;
+            let mut since_reset = 0;
+            for request in admitted {
+                if request.at_ms > last_admitted_ms.saturating_add(params.ttl * 1000) {
+                    since_reset = 0;
+                }
+                since_reset += request.cost;
+                last_admitted_ms = request.at_ms;
+                prop_assert!(since_reset <= params.limit, "admitted {} without a reset", since_reset);
+            }
+        }
+    }
+
+    #[test]
+    fn refreshing_window_expiry_follows_the_last_allowed_hit() {
+        let now = Utc::now();
+        let params = Params {
+            limit: 2,
+            ttl: 30,
+            cost: 1,
+        };
+        let mut state = RefreshingWindow.initial(now, &params);
+        assert_eq!(RefreshingWindow.check(&mut state, now, &params), Decision::Allow);
+        let later = now + Duration::seconds(20);
+        assert_eq!(RefreshingWindow.check(&mut state, later, &params), Decision::Allow);
+        assert_eq!(state.ttl, Some(later + Duration::seconds(30)));
+        let denied_at = later + Duration::seconds(10);
+        assert_eq!(
+            RefreshingWindow.check(&mut state, denied_at, &params),
+            Decision::Deny { retry_after: 20 }
+        );
+        assert_eq!(state.ttl, Some(later + Duration::seconds(30)));
+    }
+
+    proptest! {
+        #[test]
+        fn sliding_window_never_admits_more_than_limit_in_any_window((params, requests) in cases()) {
+            let admitted = admitted(&SlidingWindow, &params, &requests);
+            for (i, request) in admitted.iter().enumerate() {
+                let in_window: LimitType = admitted[..=i]
+                    .iter()
+                    .filter(|earlier| earlier.at_ms > request.at_ms - params.ttl * 1000)
+                    .map(|earlier| earlier.cost)
+                    .sum();
+                prop_assert!(in_window <= params.limit, "admitted {} within {}s", in_window, params.ttl);
+            }
+        }
+    }
+
+    #[test]
+    fn sliding_window_smooths_burst_at_window_boundary() {
+        let params = Params {
+            limit: 10,
+            ttl: 10,
+            cost: 1,
+        };
+        // a full burst just before the first window ends and another just after
+        let burst = |at_ms| (0..10).map(move |_| Request { at_ms, cost: 1 });
+        let requests: Vec<Request> = burst(9_900).chain(burst(10_100)).collect();
+        // the fixed window started at the first request so it resets between the bursts
+        let start = Request { at_ms: 0, cost: 1 };
+        let requests: Vec<Request> = std::iter::once(start).chain(requests).collect();
+        assert_eq!(admitted(&FixedWindow, &params, &requests).len(), 20);
+        assert_eq!(admitted(&SlidingWindow, &params, &requests).len(), 10);
+    }
+
+    #[test]
+    fn sliding_window_frees_quota_as_buckets_leave() {
+        let now = Utc.timestamp_opt(1_000, 0).unwrap();
+        let params = Params {
+            limit: 2,
+            ttl: 10,
+            cost: 1,
+        };
+        let mut state = SlidingWindow.initial(now, &params);
+        assert_eq!(SlidingWindow.check(&mut state, now, &params), Decision::Allow);
+        let later = now + Duration::seconds(5);
+        assert_eq!(SlidingWindow.check(&mut state, later, &params), Decision::Allow);
+        let before = state.clone();
+        assert_eq!(SlidingWindow.check(&mut state, later, &params), Decision::Deny { retry_after: 6 });
+        assert!(state == before);
+        // the first hit has left the window, the second is still in it
+        let after_first = now + Duration::seconds(11);
+        assert_eq!(SlidingWindow.check(&mut state, after_first, &params), Decision::Allow);
+        assert_eq!(state.count, 2);
+        assert_eq!(state.ttl, Some(Utc.timestamp_opt(1_022, 0).unwrap()));
+    }
+
+    fn bucket_params(capacity: LimitType) -> Params {
+        Params {
+            limit: capacity,
+            ttl: 60,
+            cost: 1,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn token_bucket_never_admits_more_than_capacity_plus_refill((params, requests) in cases()) {
+            // refilling the whole capacity once per window
+            let bucket = TokenBucket {
+                refill_per_sec: params.limit as f64 / params.ttl as f64,
+            };
+            let admitted = admitted(&bucket, &params, &requests);
+            for (i, first) in admitted.iter().enumerate() {
+                let mut taken = 0;
+                for last in &admitted[i..] {
+                    taken += last.cost;
+                    let refilled = (last.at_ms - first.at_ms) as f64 / 1000.0 * bucket.refill_per_sec;
+                    prop_assert!(
+                        taken as f64 <= params.limit as f64 + refilled + 1e-9,
+                        "admitted {} over {}ms",
+                        taken,
+                        last.at_ms - first.at_ms
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn token_bucket_allows_exactly_capacity_burst() {
+        let bucket = TokenBucket { refill_per_sec: 1.0 };
+        let params = bucket_params(3);
+        let now = Utc::now();
+        let mut state = bucket.initial(now, &params);
+        for _ in 0..3 {
+            assert_eq!(bucket.check(&mut state, now, &params), Decision::Allow);
+        }
+        assert_eq!(state.tokens, 0.0);
+        assert_eq!(state.count, 3);
+        let before = state.clone();
+        assert_eq!(bucket.check(&mut state, now, &params), Decision::Deny { retry_after: 1 });
+        assert!(state == before);
+    }
+
+    #[test]
+    fn token_bucket_refill_after_idle_is_capped_at_capacity() {
+        let bucket = TokenBucket { refill_per_sec: 2.0 };
+        let params = bucket_params(4);
+        let now = Utc::now();
+        let mut state = bucket.initial(now, &params);
+        assert_eq!(bucket.check(&mut state, now, &params), Decision::Allow);
+        assert_eq!(state.ttl, Some(now + Duration::seconds(1)));
+        // a day idle refills to capacity, not a day's worth of tokens
+        let later = now + Duration::days(1);
+        let admitted = (0..10).filter(|_| bucket.check(&mut state, later, &params) == Decision::Allow).count();
+        assert_eq!(admitted, 4);
+    }
+
+    #[test]
+    fn token_bucket_refills_fractionally_within_a_second() {
+        let bucket = TokenBucket { refill_per_sec: 4.0 };
+        let params = bucket_params(1);
+        let now = Utc::now();
+        let mut state = bucket.initial(now, &params);
+        assert_eq!(bucket.check(&mut state, now, &params), Decision::Allow);
+        let half_token = now + Duration::milliseconds(125);
+        assert_eq!(bucket.check(&mut state, half_token, &params), Decision::Deny { retry_after: 1 });
+        let one_token = now + Duration::milliseconds(250);
+        assert_eq!(bucket.check(&mut state, one_token, &params), Decision::Allow);
+        assert_eq!(state.tokens, 0.0);
+    }
+
+    proptest! {
+        #[test]
+        fn leaky_bucket_never_admits_more_than_capacity_plus_leaked((params, requests) in cases()) {
+            // draining the whole capacity once per window
+            let bucket = LeakyBucket {
+                leak_per_sec: params.limit as f64 / params.ttl as f64,
+            };
+            let admitted = admitted(&bucket, &params, &requests);
+            for (i, first) in admitted.iter().enumerate() {
+                let mut poured = 0;
+                for last in &admitted[i..] {
+                    poured += last.cost;
+                    let leaked = (last.at_ms - first.at_ms) as f64 / 1000.0 * bucket.leak_per_sec;
+                    prop_assert!(
+                        poured as f64 <= params.limit as f64 + leaked + 1e-9,
+                        "admitted {} over {}ms",
+                        poured,
+                        last.at_ms - first.at_ms
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leaky_bucket_steady_inflow_at_drain_rate_is_never_denied() {
+        let bucket = LeakyBucket { leak_per_sec: 2.0 };
+        let params = bucket_params(1);
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut state = bucket.initial(start, &params);
+        for tick in 0..100 {
+            let now = start + Duration::milliseconds(500 * tick);
+            assert_eq!(bucket.check(&mut state, now, &params), Decision::Allow);
+            assert_eq!(state.level, 1.0);
+        }
+    }
+
+    #[test]
+    fn leaky_bucket_rejects_overflow_until_enough_has_leaked() {
+        let bucket = LeakyBucket { leak_per_sec: 0.5 };
+        let params = bucket_params(3);
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut state = bucket.initial(now, &params);
+        for _ in 0..3 {
+            assert_eq!(bucket.check(&mut state, now, &params), Decision::Allow);
+        }
+        assert_eq!(state.count, 3);
+        assert_eq!(state.ttl, Some(now + Duration::seconds(6)));
+        let before = state.clone();
+        assert_eq!(bucket.check(&mut state, now, &params), Decision::Deny { retry_after: 2 });
+        let part_drained = now + Duration::seconds(1);
+        assert_eq!(bucket.check(&mut state, part_drained, &params), Decision::Deny { retry_after: 1 });
+        assert!(state == before);
+        let drained = now + Duration::seconds(2);
+        assert_eq!(bucket.check(&mut state, drained, &params), Decision::Allow);
+        assert_eq!(state.level, 3.0);
+    }
+
+    #[test]
+    fn leaky_bucket_recovers_after_idle_without_going_below_empty() {
+        let bucket = LeakyBucket { leak_per_sec: 1.0 };
+        let params = bucket_params(4);
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut state = bucket.initial(now, &params);
+        let admitted = (0..10).filter(|_| bucket.check(&mut state, now, &params) == Decision::Allow).count();
+        assert_eq!(admitted, 4);
+        // a day idle empties the bucket, it doesn't bank a day's worth of room
+        let later = now + Duration::days(1);
+        let admitted = (0..10).filter(|_| bucket.check(&mut state, later, &params) == Decision::Allow).count();
+        assert_eq!(admitted, 4);
+    }
+
+    proptest! {
+        #[test]
+        fn gcra_never_admits_more_than_burst_plus_paced((params, requests) in cases()) {
+            // pacing the whole burst out over one window
+            let interval_ms = params.ttl * 1000 / params.limit;
+            let gcra = Gcra::every(interval_ms as f64 / 1000.0);
+            let admitted = admitted(&gcra, &params, &requests);
+            for (i, first) in admitted.iter().enumerate() {
+                let mut taken = 0;
+                for last in &admitted[i..] {
+                    taken += last.cost;
+                    let elapsed_ms = last.at_ms - first.at_ms;
+                    prop_assert!(
+                        taken * interval_ms <= params.limit * interval_ms + elapsed_ms,
+                        "admitted {} over {}ms",
+                        taken,
+                        elapsed_ms
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gcra_admits_a_burst_then_paces() {
+        let gcra = Gcra::every(2.0);
+        let params = bucket_params(3);
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut state = gcra.initial(now, &params);
+        for _ in 0..3 {
+            assert_eq!(gcra.check(&mut state, now, &params), Decision::Allow);
+        }
+        assert_eq!(state.tat, Some(now + Duration::seconds(6)));
+        assert_eq!(state.count, 3);
+        let before = state.clone();
+        assert_eq!(gcra.check(&mut state, now, &params), Decision::Deny { retry_after: 2 });
+        let early = now + Duration::milliseconds(500);
+        assert_eq!(gcra.check(&mut state, early, &params), Decision::Deny { retry_after: 2 });
+        assert!(state == before);
+        let on_time = now + Duration::seconds(2);
+        assert_eq!(gcra.check(&mut state, on_time, &params), Decision::Allow);
+        assert_eq!(state.tat, Some(now + Duration::seconds(8)));
+    }
+
+    #[test]
+    fn gcra_steady_conforming_traffic_is_never_denied() {
+        let gcra = Gcra::every(0.25);
+        let params = bucket_params(1);
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut state = gcra.initial(start, &params);
+        for tick in 0..100 {
+            let now = start + Duration::milliseconds(250 * tick);
+            assert_eq!(gcra.check(&mut state, now, &params), Decision::Allow);
+            assert_eq!(state.count, 1);
+        }
+        // one interval early is too soon with no burst to spare
+        let now = start + Duration::milliseconds(250 * 99);
+        assert_eq!(gcra.check(&mut state, now, &params), Decision::Deny { retry_after: 1 });
+    }
+
+    #[test]
+    fn usage_carries_over_between_every_pair_of_algorithms() {
+        let algorithms: [&dyn Algorithm; 6] = [
+            &FixedWindow,
+            &RefreshingWindow,
+            &SlidingWindow,
+            &TokenBucket { refill_per_sec: 0.5 },
+            &LeakyBucket { leak_per_sec: 0.5 },
+            &Gcra::every(2.0),
+        ];
+        let params = Params {
+            limit: 5,
+            ttl: 10,
+            cost: 1,
+        };
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        for (i, from) in algorithms.iter().enumerate() {
+            let mut state = from.initial(now, &params);
+            for _ in 0..3 {
+                assert_eq!(from.check(&mut state, now, &params), Decision::Allow);
+            }
+            let usage = from.usage(&state, now, &params);
+            assert_eq!(usage.used, 3, "algorithm {}", i);
+            for (j, to) in algorithms.iter().enumerate() {
+                let mut state = to.with_usage(usage, now, &params);
+                assert_eq!(to.usage(&state, now, &params).used, 3, "algorithm {} to {}", i, j);
+                // the two units left are still there, and no more
+                for _ in 0..2 {
+                    assert_eq!(to.check(&mut state, now, &params), Decision::Allow, "algorithm {} to {}", i, j);
+                }
+                assert_ne!(to.check(&mut state, now, &params), Decision::Allow, "algorithm {} to {}", i, j);
+            }
+        }
+    }
+}
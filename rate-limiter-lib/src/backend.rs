@@ -0,0 +1,249 @@
+use crate::{
+    IncOutcome,
+    InternalValue,
+    KeyType,
+    LimitType,
+    MemoryPolicy,
+    ModelError,
+    Quota,
+    SharedWriter,
+    Store,
+    StoredValue,
+    WindowMode,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use evmap::ReadHandleFactory;
+use std::sync::Arc;
+
+/// Storage the rate limits are enforced against. Handlers, middleware and the admin routes all go
+/// through this trait rather than the evmap handles so a backend shared between replicas can be
+/// swapped in without touching them. The methods are async so a backend that is a network round
+/// trip away awaits its replies instead of blocking the runtime's workers. MemoryStore keeps the
+/// limits in process, RedisStore (behind the `redis` feature) shares them between replicas.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    /// The backend's current time, every ttl it reports is relative to it
+    fn now(&self) -> DateTime<Utc>;
+
+    /// See Store::inc_below_limit
+    async fn inc_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError>;
+
+    /// See Store::inc_by_below_limit
+    async fn inc_by_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        cost: LimitType,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError>;
+
+    /// See Store::inc_all_below_limit
+    async fn inc_all_below_limit(
+        &self,
+        items: &[(KeyType, LimitType, i64)],
+        mode: WindowMode,
+    ) -> Result<Vec<Quota>, ModelError>;
+
+    /// See Store::inc_by
+    async fn inc_by(&self, key: KeyType, amount: LimitType, ttl: i64, mode: WindowMode) -> Result<(), ModelError>;
+
+    /// See Store::refund
+    async fn refund(&self, key: KeyType, amount: LimitType) -> Result<(), ModelError>;
+
+    async fn insert(&self, key: &KeyType, count: LimitType, ttl: i64) -> Result<(), ModelError>;
+
+    /// See Store::set_tag
+    async fn set_tag(&self, key: KeyType, tag: Option<String>) -> Result<(), ModelError>;
+
+    /// See Store::reserve_memory
+    async fn reserve_memory(
+        &self,
+        key: &KeyType,
+        max_memory_bytes: usize,
+        policy: MemoryPolicy,
+    ) -> Result<(), ModelError>;
+
+    async fn delete(&self, key: &KeyType) -> Result<(), ModelError>;
+
+    /// See Store::delete_prefix
+    async fn delete_prefix(&self, prefix: &str) -> usize;
+
+    async fn get(&self, key: &KeyType) -> Result<Option<StoredValue>, ModelError>;
+
+    /// See Store::remaining
+    async fn remaining(
+        &self,
+        key: &KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<LimitType, ModelError>;
+
+    /// See Store::keys
+    async fn keys(&self) -> Vec<KeyType>;
+
+    /// Number of keys being tracked
+    async fn len(&self) -> usize;
+
+    async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// See Store::estimated_memory_bytes
+    async fn estimated_memory_bytes(&self) -> usize;
+}
+
+/// The in process evmap Store, each instance enforces its limits on its own
+pub struct MemoryStore {
+    reader: ReadHandleFactory<KeyType, InternalValue>,
+    writer: Arc<SharedWriter>,
+}
+
+impl MemoryStore {
+    pub fn new(reader: ReadHandleFactory<KeyType, InternalValue>, writer: Arc<SharedWriter>) -> Self {
+        MemoryStore { reader, writer }
+    }
+}
+
+#[async_trait]
+impl RateLimiter for MemoryStore {
+    fn now(&self) -> DateTime<Utc> {
+        self.writer.now()
+    }
+
+    async fn inc_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError> {
+        Store::inc_below_limit(&self.writer, &self.reader.handle(), key, limit, ttl, mode).map(IncOutcome::quota)
+    }
+
+    async fn inc_by_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        cost: LimitType,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError> {
+        let reader = self.reader.handle();
+        Store::inc_by_below_limit(&self.writer, &reader, key, limit, ttl, cost, mode).map(IncOutcome::quota)
+    }
+
+    async fn inc_all_below_limit(
+        &self,
+        items: &[(KeyType, LimitType, i64)],
+        mode: WindowMode,
+    ) -> Result<Vec<Quota>, ModelError> {
+        Store::inc_all_below_limit(&self.writer, &self.reader.handle(), items, mode)
+    }
+
+    async fn inc_by(&self, key: KeyType, amount: LimitType, ttl: i64, mode: WindowMode) -> Result<(), ModelError> {
+        Store::inc_by(&self.writer, key, amount, ttl, mode)
+    }
+
+    async fn refund(&self, key: KeyType, amount: LimitType) -> Result<(), ModelError> {
+        Store::refund(&self.writer, &self.reader.handle(), key, amount)
+    }
+
+    async fn insert(&self, key: &KeyType, count: LimitType, ttl: i64) -> Result<(), ModelError> {
+        Store::insert(&self.writer, key, count, ttl)
+    }
+
+    async fn set_tag(&self, key: KeyType, tag: Option<String>) -> Result<(), ModelError> {
+        Store::set_tag(&self.writer, key, tag)
+    }
+
+    async fn reserve_memory(
+        &self,
+        key: &KeyType,
+        max_memory_bytes: usize,
+        policy: MemoryPolicy,
+    ) -> Result<(), ModelError> {
+        Store::reserve_memory(&self.writer, &self.reader.handle(), key, max_memory_bytes, policy)
+    }
+
+    async fn delete(&self, key: &KeyType) -> Result<(), ModelError> {
+        Store::delete(&self.writer, key)
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> usize {
+        Store::delete_prefix(&self.writer, &self.reader.handle(), prefix)
+    }
+
+    async fn get(&self, key: &KeyType) -> Result<Option<StoredValue>, ModelError> {
+        Store::get(&self.reader.handle(), key)
+    }
+
+    async fn remaining(
+        &self,
+        key: &KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<LimitType, ModelError> {
+        Store::remaining(&self.reader.handle(), key, limit, ttl, mode, self.writer.now())
+    }
+
+    async fn keys(&self) -> Vec<KeyType> {
+        Store::keys(&self.reader.handle())
+    }
+
+    async fn len(&self) -> usize {
+        Store::len(&self.reader.handle())
+    }
+
+    async fn estimated_memory_bytes(&self) -> usize {
+        Store::estimated_memory_bytes(&self.reader.handle())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store_round_trips_through_the_trait() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let store: Arc<dyn RateLimiter + Send + Sync> = Arc::new(MemoryStore::new(read_handle, write_handle));
+        let key = "add_vault_item:backend".to_string();
+        store.insert(&key, 1, 60).await.unwrap();
+        assert!(matches!(store.insert(&key, 1, 60).await, Err(ModelError::AlreadyPresent)));
+        store.inc_below_limit(key.clone(), 3, 60, WindowMode::Fixed).await.unwrap();
+        let quota = store.inc_by_below_limit(key.clone(), 3, 60, 1, WindowMode::Fixed).await.unwrap();
+        assert_eq!(quota.remaining, 0);
+        let result = store.inc_below_limit(key.clone(), 3, 60, WindowMode::Fixed).await;
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        assert_eq!(store.get(&key).await.unwrap().map(|v| v.count), Some(3));
+        assert_eq!(store.remaining(&key, 5, 60, WindowMode::Fixed).await.unwrap(), 2);
+        let total = "vault:backend".to_string();
+        let items = [(total.clone(), 5, 60), (key.clone(), 3, 60)];
+        let result = store.inc_all_below_limit(&items, WindowMode::Fixed).await;
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        assert!(store.get(&total).await.unwrap().is_none());
+        store.refund(key.clone(), 2).await.unwrap();
+        store.inc_by(key.clone(), 4, 60, WindowMode::Fixed).await.unwrap();
+        store.set_tag(key.clone(), Some("vip".to_string())).await.unwrap();
+        let stored_value = store.get(&key).await.unwrap().unwrap();
+        assert_eq!((stored_value.count, stored_value.tag.as_deref()), (5, Some("vip")));
+        assert_eq!(store.keys().await, vec![key.clone()]);
+        assert_eq!(store.len().await, 1);
+        assert_eq!(store.estimated_memory_bytes().await, crate::ESTIMATED_ENTRY_BYTES);
+        let result = store.reserve_memory(&"add_vault_item:other".to_string(), 0, MemoryPolicy::Reject).await;
+        assert!(matches!(result, Err(ModelError::StoreFull)));
+        store.delete(&key).await.unwrap();
+        assert!(store.get(&key).await.unwrap().is_none());
+        assert!(store.is_empty().await);
+    }
+}
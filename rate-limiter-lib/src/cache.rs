@@ -0,0 +1,309 @@
+use crate::{
+    Clock,
+    KeyType,
+    LimitType,
+    MemoryPolicy,
+    ModelError,
+    Params,
+    Quota,
+    RateLimiter,
+    Store,
+    StoredValue,
+    WindowMode,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+/// A key's state as last read from the backend, plus what has been charged locally since
+struct CachedEntry {
+    value: StoredValue,
+    fetched_at: DateTime<Utc>,
+    /// Units charged against `value` that the backend hasn't seen yet
+    pending: LimitType,
+    /// Window the pending units are charged to the backend with
+    ttl: i64,
+    mode: WindowMode,
+}
+
+/// Local write-back cache in front of a backend where every call is a round trip (e.g. RedisStore),
+/// in front of MemoryStore it would only add work. A key's state is read from the backend on its
+/// first charge and for `cache_ttl` after that its charges are decided against the local copy,
+/// denials included, so a caller the backend has refused is refused locally until the copy goes
+/// stale. Charges made locally are pushed to the backend by sync, which is meant to run on a timer,
+/// and before anything else touches the key. Until then replicas don't see each other's local
+/// charges, so a limit can be overshot by what each of them lets through within one cache ttl.
+/// Everything but charges goes to the backend. Clones share the same cache.
+#[derive(Clone)]
+pub struct CachedStore {
+    inner: Arc<dyn RateLimiter + Send + Sync>,
+    clock: Arc<dyn Clock>,
+    cache_ttl: Duration,
+    entries: Arc<Mutex<HashMap<KeyType, CachedEntry>>>,
+}
+
+impl CachedStore {
+    pub fn new(inner: Arc<dyn RateLimiter + Send + Sync>, clock: Arc<dyn Clock>, cache_ttl: Duration) -> Self {
+        CachedStore {
+            inner,
+            clock,
+            cache_ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Push every locally charged unit to the backend and forget the entries that have gone stale.
+    /// Returns how many keys were pushed, or the last error the backend returned. Units the backend
+    /// refused are dropped rather than retried, they would only be refused again.
+    pub async fn sync(&self) -> Result<usize, ModelError> {
+        let now = self.clock.now();
+        let pending: Vec<_> = {
+            let mut entries = self.entries.lock();
+            let pending = entries
+                .iter_mut()
+                .filter(|(_, entry)| entry.pending > 0)
+                .map(|(key, entry)| (key.clone(), std::mem::take(&mut entry.pending), entry.ttl, entry.mode))
+                .collect();
+            entries.retain(|_, entry| self.is_fresh(entry, now));
+            pending
+        };
+        let mut result = Ok(0);
+        for (key, amount, ttl, mode) in pending {
+            match (self.inner.inc_by(key, amount, ttl, mode).await, &mut result) {
+                (Ok(()), Ok(synced)) => *synced += 1,
+                (Ok(()), Err(_)) => {},
+                (Err(e), _) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    /// Drop the key's entry, pushing its local charges to the backend first
+    async fn evict(&self, key: &KeyType) -> Result<(), ModelError> {
+        let entry = self.entries.lock().remove(key);
+        match entry {
+            Some(entry) if entry.pending > 0 => {
+                self.inner.inc_by(key.clone(), entry.pending, entry.ttl, entry.mode).await
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn is_fresh(&self, entry: &CachedEntry, now: DateTime<Utc>) -> bool {
+        now < entry.fetched_at + self.cache_ttl && entry.value.ttl.is_none_or(|ttl| ttl > now)
+    }
+
+    async fn charge(&self, key: KeyType, params: Params, mode: WindowMode) -> Result<Quota, ModelError> {
+        let now = self.clock.now();
+        if let Some(result) = self.charge_locally(&key, &params, mode, now) {
+            return result;
+        }
+        self.evict(&key).await?;
+        let result = self
+            .inner
+            .inc_by_below_limit(key.clone(), params.limit, params.ttl, params.cost, mode)
+            .await;
+        // denials are cached too so a caller hammering a limited key doesn't go to the backend
+        if let Some(value) = self.inner.get(&key).await? {
+            let entry = CachedEntry {
+                value,
+                fetched_at: now,
+                pending: 0,
+                ttl: params.ttl,
+                mode,
+            };
+            self.entries.lock().insert(key, entry);
+        }
+        result
+    }
+
+    /// Decide the charge against the key's cached state, None when it has none that is fresh
+    fn charge_locally(
+        &self,
+        key: &KeyType,
+        params: &Params,
+        mode: WindowMode,
+        now: DateTime<Utc>,
+    ) -> Option<Result<Quota, ModelError>> {
+        let mut entries = self.entries.lock();
+        let entry = entries
+            .get_mut(key)
+            .filter(|entry| entry.mode == mode && self.is_fresh(entry, now))?;
+        Some(Store::charge(mode.algorithm(), Some(entry.value.clone()), params, now).map(|value| {
+            entry.value = value;
+            entry.pending += params.cost;
+            Quota::of(&entry.value, params.limit, now)
+        }))
+    }
+}
+
+#[async_trait]
+impl RateLimiter for CachedStore {
+    fn now(&self) -> DateTime<Utc> {
+        self.inner.now()
+    }
+
+    async fn inc_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError> {
+        self.charge(key, Params { limit, ttl, cost: 1 }, mode).await
+    }
+
+    async fn inc_by_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        cost: LimitType,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError> {
+        if cost < 1 {
+            return Err(ModelError::InvalidCost(cost));
+        }
+        self.charge(key, Params { limit, ttl, cost }, mode).await
+    }
+
+    /// Batches always go to the backend so every key is checked against its latest state
+    async fn inc_all_below_limit(
+        &self,
+        items: &[(KeyType, LimitType, i64)],
+        mode: WindowMode,
+    ) -> Result<Vec<Quota>, ModelError> {
+        for (key, _, _) in items {
+            self.evict(key).await?;
+        }
+        self.inner.inc_all_below_limit(items, mode).await
+    }
+
+    async fn inc_by(&self, key: KeyType, amount: LimitType, ttl: i64, mode: WindowMode) -> Result<(), ModelError> {
+        self.evict(&key).await?;
+        self.inner.inc_by(key, amount, ttl, mode).await
+    }
+
+    async fn refund(&self, key: KeyType, amount: LimitType) -> Result<(), ModelError> {
+        self.evict(&key).await?;
+        self.inner.refund(key, amount).await
+    }
+
+    async fn insert(&self, key: &KeyType, count: LimitType, ttl: i64) -> Result<(), ModelError> {
+        self.evict(key).await?;
+        self.inner.insert(key, count, ttl).await
+    }
+
+    async fn set_tag(&self, key: KeyType, tag: Option<String>) -> Result<(), ModelError> {
+        self.evict(&key).await?;
+        self.inner.set_tag(key, tag).await
+    }
+
+    async fn reserve_memory(
+        &self,
+        key: &KeyType,
+        max_memory_bytes: usize,
+        policy: MemoryPolicy,
+    ) -> Result<(), ModelError> {
+        self.inner.reserve_memory(key, max_memory_bytes, policy).await
+    }
+
+    async fn delete(&self, key: &KeyType) -> Result<(), ModelError> {
+        // the key is going away, there is no point in pushing its local charges
+        self.entries.lock().remove(key);
+        self.inner.delete(key).await
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> usize {
+        self.entries.lock().retain(|key, _| !key.starts_with(prefix));
+        self.inner.delete_prefix(prefix).await
+    }
+
+    async fn get(&self, key: &KeyType) -> Result<Option<StoredValue>, ModelError> {
+        self.evict(key).await?;
+        self.inner.get(key).await
+    }
+
+    async fn remaining(
+        &self,
+        key: &KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<LimitType, ModelError> {
+        self.evict(key).await?;
+        self.inner.remaining(key, limit, ttl, mode).await
+    }
+
+    async fn keys(&self) -> Vec<KeyType> {
+        self.inner.keys().await
+    }
+
+    async fn len(&self) -> usize {
+        self.inner.len().await
+    }
+
+    async fn estimated_memory_bytes(&self) -> usize {
+        self.inner.estimated_memory_bytes().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{scoped_key, ManualClock, MemoryStore};
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn checks_within_the_cache_ttl_stay_local_until_synced() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let backend: Arc<dyn RateLimiter + Send + Sync> = Arc::new(MemoryStore::new(read_handle, write_handle));
+        let cache = CachedStore::new(backend.clone(), clock.clone(), Duration::milliseconds(50));
+        let key = scoped_key("get_vault_items", "cached");
+        let backend_count = || async { backend.get(&key).await.unwrap().map(|v| v.count) };
+
+        // the first check reads through, the next ones are decided locally
+        for remaining in [3, 2, 1, 0] {
+            let quota = cache.inc_below_limit(key.clone(), 4, 60, WindowMode::Fixed).await.unwrap();
+            assert_eq!(quota.remaining, remaining);
+            assert_eq!(backend_count().await, Some(1));
+        }
+        let result = cache.inc_below_limit(key.clone(), 4, 60, WindowMode::Fixed).await;
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 60, limit: 4 })));
+        assert_eq!(backend_count().await, Some(1));
+
+        assert_eq!(cache.sync().await.unwrap(), 1);
+        assert_eq!(backend_count().await, Some(4));
+        assert_eq!(cache.sync().await.unwrap(), 0);
+
+        // once the cache ttl is up the backend is asked again, and sees a charge made around the cache
+        backend.refund(key.clone(), 2).await.unwrap();
+        clock.advance(Duration::milliseconds(50));
+        let quota = cache.inc_below_limit(key.clone(), 4, 60, WindowMode::Fixed).await.unwrap();
+        assert_eq!(quota.remaining, 1);
+        assert_eq!(backend_count().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_push_local_charges_first() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let backend: Arc<dyn RateLimiter + Send + Sync> = Arc::new(MemoryStore::new(read_handle, write_handle));
+        let cache = CachedStore::new(backend.clone(), clock, Duration::seconds(1));
+        let key = scoped_key("add_vault_item", "cached");
+        for _ in 0..3 {
+            cache.inc_below_limit(key.clone(), 5, 60, WindowMode::Sliding).await.unwrap();
+        }
+        assert_eq!(cache.get(&key).await.unwrap().map(|v| v.count), Some(3));
+        cache.inc_below_limit(key.clone(), 5, 60, WindowMode::Sliding).await.unwrap();
+        cache.refund(key.clone(), 1).await.unwrap();
+        assert_eq!(backend.get(&key).await.unwrap().map(|v| v.count), Some(3));
+        cache.delete(&key).await.unwrap();
+        assert_eq!(cache.sync().await.unwrap(), 0);
+        assert!(backend.get(&key).await.unwrap().is_none());
+    }
+}
@@ -1,25 +1,257 @@
+mod algorithms;
+mod backend;
+mod cache;
+mod clock;
+#[cfg(feature = "redis")]
+mod redis;
+
+pub use algorithms::{
+    Algorithm,
+    Decision,
+    FixedWindow,
+    Gcra,
+    LeakyBucket,
+    Params,
+    RefreshingWindow,
+    SlidingWindow,
+    TokenBucket,
+    Usage,
+    WindowMode,
+};
+pub use backend::{MemoryStore, RateLimiter};
+pub use cache::CachedStore;
+pub use clock::{Clock, ManualClock, SystemClock};
+#[cfg(feature = "redis")]
+pub use redis::{RedisStore, REDIS_KEY_PREFIX};
 use chrono::{DateTime, Duration, Utc};
 use evmap::{ReadHandle, ReadHandleFactory, WriteHandle};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, MutexGuard};
 use priority_queue::double_priority_queue::DoublePriorityQueue;
-use std::{error::Error, fmt, sync::Arc};
-use tokio::{task, task::JoinHandle};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    fs,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    mem::size_of,
+    net::IpAddr,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::Arc,
+};
+use tokio::{
+    sync::{oneshot, Notify},
+    task,
+    task::JoinHandle,
+    time::{self, Duration as StdDuration},
+};
 
 #[derive(Debug)]
 pub enum ModelError {
     NotFound,
     AlreadyPresent,
-    PastRateLimit(i64),
+    /// Seconds until the key would allow the request again, at least 1, and the limit that was in
+    /// effect
+    PastRateLimit { retry_after_secs: i64, limit: LimitType },
+    /// The key is at its limit and never expires, so there is no time after which it is allowed again
+    LimitExhausted { limit: LimitType },
+    WouldBlock,
+    StoreFull,
+    /// The caller has been blocklisted and is refused regardless of its quota
+    Blocked,
+    /// A charge must cost at least one unit
+    InvalidCost(LimitType),
+    /// Limits must allow at least one request
+    InvalidLimit(LimitType),
+    /// A window length in seconds that is out of the accepted range
+    InvalidTtl(i64),
+    /// Refill, leak and pacing rates must be positive and finite
+    InvalidRate(f64),
+    /// Counts can't start out negative
+    InvalidCount(LimitType),
+    /// An unconditional charge (inc_by) would take the count past LimitType::MAX
+    Overflow,
+    /// Tags are at most MAX_TAG_BYTES long, carries the length that was given
+    TagTooLong(usize),
+    /// A remote backend couldn't be reached or answered with something unexpected, carries why
+    Unavailable(String),
 }
 
 pub type KeyType = String;
+
+/// What a Store can be keyed by. The HTTP layer uses KeyType but any key evmap and the ttl queue
+/// can hold works, e.g. a numeric account id.
+pub trait StoreKey: Eq + Hash + Clone + Send + Sync + 'static {}
+
+impl<K: Eq + Hash + Clone + Send + Sync + 'static> StoreKey for K {}
 pub type LimitType = i64;
 pub type InternalValue = Box<StoredValue>;
+/// What Store::init hands back: the reader factory, the writer, the reconcile loop and the sender
+/// that stops it
+pub type StoreHandles<K> = (
+    ReadHandleFactory<K, InternalValue>,
+    Arc<SharedWriter<K>>,
+    JoinHandle<()>,
+    oneshot::Sender<()>,
+);
+
+/// Longest the main loop sleeps when no key is due to expire sooner
+const MAX_RECONCILE_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// Most keys the main loop evicts while holding the writer lock before publishing them and letting
+/// requests in, large enough that a small store clears everything that is due in one pass
+pub const DEFAULT_EVICT_BATCH_SIZE: usize = 10_000;
+
+/// Keys save_snapshot_in_chunks writes before letting other tasks run
+pub const DEFAULT_SNAPSHOT_CHUNK_SIZE: usize = 1_000;
+
+/// Separates the scope (e.g. the route) from the caller id in a key. Scopes never contain it so the
+/// first occurrence always ends the scope even when the caller id contains the separator.
+pub const SCOPE_SEPARATOR: char = ':';
+
+/// Longest tag a bucket can carry, tags are short labels and are stored twice like the rest of the
+/// value
+pub const MAX_TAG_BYTES: usize = 64;
 
-#[derive(Eq, PartialEq, Hash, Clone)]
+/// Version of the format save_snapshot writes. Version 1 predates the envelope and was a bare array
+/// of entries, load_snapshot still reads it.
+pub const SNAPSHOT_VERSION: u64 = 2;
+
+/// Key length assumed when estimating memory, long enough for a scope and a bearer token
+const ESTIMATED_KEY_BYTES: usize = 48;
+
+/// Rough number of bytes a tracked key costs. evmap keeps two copies of the map so the key and the
+/// boxed value pointer are counted twice, on top of the value itself and the key's ttl queue and
+/// recency queue entries.
+pub const ESTIMATED_ENTRY_BYTES: usize = 2 * (size_of::<KeyType>() + ESTIMATED_KEY_BYTES + size_of::<InternalValue>()) +
+    size_of::<StoredValue>() +
+    2 * (size_of::<KeyType>() + ESTIMATED_KEY_BYTES) +
+    size_of::<DateTime<Utc>>() +
+    size_of::<u64>();
+
+/// What to do with a new key once the store has reached its memory budget
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryPolicy {
+    /// Refuse to track the new key
+    #[default]
+    Reject,
+    /// Make room by dropping the keys closest to expiring, they have the least quota left to lose
+    EvictSoonestExpiring,
+    /// Make room by dropping the keys that have gone the longest without being charged or written
+    EvictLeastRecentlyUsed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct StoredValue {
     pub count: LimitType,
     pub ttl: Option<DateTime<Utc>>,
+    /// Opaque operator supplied annotation (e.g. "flagged", "vip") carried along with the bucket
+    pub tag: Option<String>,
+    /// Per-second hit counts (unix second, units) used by the sliding window, empty in fixed mode
+    pub buckets: Vec<(i64, LimitType)>,
+    /// Tokens left as of last_refill, only used by the token bucket
+    pub tokens: f64,
+    pub last_refill: Option<DateTime<Utc>>,
+    /// Theoretical arrival time of the next conforming request, only used by GCRA
+    pub tat: Option<DateTime<Utc>>,
+    /// Fill level as of last_leak, only used by the leaky bucket
+    pub level: f64,
+    pub last_leak: Option<DateTime<Utc>>,
+}
+
+// evmap needs values to be Eq and Hash, which f64 isn't, so tokens and levels are compared by their bits
+impl PartialEq for StoredValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.count == other.count &&
+            self.ttl == other.ttl &&
+            self.tag == other.tag &&
+            self.buckets == other.buckets &&
+            self.tokens.to_bits() == other.tokens.to_bits() &&
+            self.last_refill == other.last_refill &&
+            self.tat == other.tat &&
+            self.level.to_bits() == other.level.to_bits() &&
+            self.last_leak == other.last_leak
+    }
+}
+
+impl Eq for StoredValue {}
+
+impl Hash for StoredValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.count.hash(state);
+        self.ttl.hash(state);
+        self.tag.hash(state);
+        self.buckets.hash(state);
+        self.tokens.to_bits().hash(state);
+        self.last_refill.hash(state);
+        self.tat.hash(state);
+        self.level.to_bits().hash(state);
+        self.last_leak.hash(state);
+    }
+}
+
+/// Where a caller stands after a charge: how much of the limit is left and when it is fully
+/// restored, which is the key's ttl for every algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub remaining: LimitType,
+    pub reset_at: DateTime<Utc>,
+}
+
+impl Quota {
+    /// The quota left by the stored value as of `now`, keys that never expire are reported as
+    /// resetting now since there is no later time to give
+    pub fn of(stored_value: &StoredValue, limit: LimitType, now: DateTime<Utc>) -> Self {
+        Quota {
+            remaining: (limit - stored_value.count).max(0),
+            reset_at: stored_value.ttl.unwrap_or(now),
+        }
+    }
+}
+
+/// What an allowed charge did to the key, so callers can tell a fresh window from a repeat hit
+/// (e.g. to emit a "window started" event). Both carry the same quota as Quota::of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncOutcome {
+    /// The key wasn't tracked, this charge started it
+    Created { remaining: LimitType, reset_at: DateTime<Utc> },
+    /// The key was already tracked and has been charged again
+    Incremented { remaining: LimitType, reset_at: DateTime<Utc> },
+}
+
+impl IncOutcome {
+    fn new(created: bool, quota: Quota) -> Self {
+        let Quota { remaining, reset_at } = quota;
+        if created {
+            IncOutcome::Created { remaining, reset_at }
+        } else {
+            IncOutcome::Incremented { remaining, reset_at }
+        }
+    }
+
+    pub fn quota(self) -> Quota {
+        match self {
+            IncOutcome::Created { remaining, reset_at } | IncOutcome::Incremented { remaining, reset_at } => {
+                Quota { remaining, reset_at }
+            },
+        }
+    }
+}
+
+impl StoredValue {
+    /// A fresh bucket whose window starts at `now` and lasts `ttl` seconds
+    pub fn new(count: LimitType, ttl: i64, now: DateTime<Utc>) -> Self {
+        StoredValue {
+            count,
+            ttl: Some(now + Duration::seconds(ttl)),
+            ..Default::default()
+        }
+    }
 }
 
 impl fmt::Display for ModelError {
@@ -27,148 +259,2254 @@ impl fmt::Display for ModelError {
         match self {
             ModelError::NotFound => write!(f, "Key Not Found"),
             ModelError::AlreadyPresent => write!(f, "Key is not present in the data set"),
-            ModelError::PastRateLimit(time_remaining) => {
-                write!(f, "Rate limit exceeded please wait {} seconds", time_remaining)
+            ModelError::PastRateLimit { retry_after_secs, limit } => {
+                write!(f, "Rate limit of {} exceeded please wait {} seconds", limit, retry_after_secs)
             },
+            ModelError::LimitExhausted { limit } => write!(f, "Rate limit of {} exceeded and does not reset", limit),
+            ModelError::WouldBlock => write!(f, "Store is busy please retry"),
+            ModelError::StoreFull => write!(f, "Store is at capacity please retry later"),
+            ModelError::Blocked => write!(f, "Caller is blocked"),
+            ModelError::InvalidCost(cost) => write!(f, "Cost must be at least 1 but was {}", cost),
+            ModelError::InvalidLimit(limit) => write!(f, "Limit must be at least 1 but was {}", limit),
+            ModelError::InvalidTtl(ttl) => write!(f, "Window of {} seconds is out of range", ttl),
+            ModelError::InvalidRate(rate) => write!(f, "Rate must be positive and finite but was {}", rate),
+            ModelError::InvalidCount(count) => write!(f, "Count must not be negative but was {}", count),
+            ModelError::Overflow => write!(f, "Count is too large to charge"),
+            ModelError::TagTooLong(len) => write!(f, "Tag must be at most {} bytes but was {}", MAX_TAG_BYTES, len),
+            ModelError::Unavailable(reason) => write!(f, "Store is unavailable: {}", reason),
         }
     }
 }
 
 impl Error for ModelError {}
 
+impl ModelError {
+    /// Stable machine readable name of the error, unlike the Display text it is safe to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            ModelError::NotFound => "not_found",
+            ModelError::AlreadyPresent => "already_present",
+            ModelError::PastRateLimit { .. } => "rate_limited",
+            ModelError::LimitExhausted { .. } => "limit_exhausted",
+            ModelError::WouldBlock => "busy",
+            ModelError::StoreFull => "store_full",
+            ModelError::Blocked => "blocked",
+            ModelError::InvalidCost(_) => "invalid_cost",
+            ModelError::InvalidLimit(_) => "invalid_limit",
+            ModelError::InvalidTtl(_) => "invalid_ttl",
+            ModelError::InvalidRate(_) => "invalid_rate",
+            ModelError::InvalidCount(_) => "invalid_count",
+            ModelError::Overflow => "overflow",
+            ModelError::TagTooLong(_) => "tag_too_long",
+            ModelError::Unavailable(_) => "unavailable",
+        }
+    }
+}
+
+/// Build the key a caller is tracked under for the given scope
+pub fn scoped_key(scope: &str, id: &str) -> KeyType {
+    format!("{}{}{}", scope, SCOPE_SEPARATOR, id)
+}
+
+/// The scope portion of a key built by scoped_key
+pub fn key_scope(key: &str) -> Option<&str> {
+    key.split_once(SCOPE_SEPARATOR).map(|(scope, _)| scope)
+}
+
+/// Resolve the address of the client a request originated from. Each trusted proxy appends the
+/// address it received the request from to `X-Forwarded-For`, so with `n` trusted proxies (the peer
+/// being the nearest) the client is the `n`th entry from the right. Anything further left was
+/// supplied by the client and can be spoofed, and with no trusted proxies the header is ignored.
+pub fn client_ip(forwarded_for: Option<&str>, peer: IpAddr, trusted_proxy_count: usize) -> IpAddr {
+    if trusted_proxy_count == 0 {
+        return peer;
+    }
+    let hops: Vec<&str> = forwarded_for
+        .map(|header| header.split(',').map(str::trim).filter(|hop| !hop.is_empty()).collect())
+        .unwrap_or_default();
+    // a shorter chain than configured means the furthest known hop is the best guess
+    let index = hops.len().saturating_sub(trusted_proxy_count);
+    hops.get(index).and_then(|hop| hop.parse().ok()).unwrap_or(peer)
+}
+
+/// The evmap write handle together with the ttl queue of every key written through it. Keeping the
+/// queue behind the same lock as the handle means each write schedules or drops its own expiry, so
+/// writes can be refreshed (made visible to readers) immediately without the main loop missing a
+/// ttl that only showed up in the pending operations.
+pub struct StoreWriter<K: StoreKey = KeyType> {
+    handle: WriteHandle<K, InternalValue>,
+    ttl_queue: DoublePriorityQueue<K, DateTime<Utc>>,
+    /// Keys by the sequence number of their latest write, the lowest was written the longest ago
+    last_used: DoublePriorityQueue<K, u64>,
+    next_use: u64,
+    /// Wakes the main loop when a write schedules an expiry earlier than the one it sleeps until
+    next_expiry_changed: Arc<Notify>,
+    /// Most keys the store may hold, see SharedWriter::set_max_keys
+    max_keys: Option<usize>,
+    #[cfg(test)]
+    reconcile_passes: usize,
+}
+
+impl<K: StoreKey> StoreWriter<K> {
+    fn new(handle: WriteHandle<K, InternalValue>) -> Self {
+        StoreWriter {
+            handle,
+            ttl_queue: DoublePriorityQueue::new(),
+            last_used: DoublePriorityQueue::new(),
+            next_use: 0,
+            next_expiry_changed: Arc::new(Notify::new()),
+            max_keys: None,
+            #[cfg(test)]
+            reconcile_passes: 0,
+        }
+    }
+
+    /// Add the value for the key, schedule its expiry and mark it as the most recently used
+    pub fn insert(&mut self, key: K, value: InternalValue) -> &mut Self {
+        self.last_used.push(key.to_owned(), self.next_use);
+        self.next_use += 1;
+        match value.ttl {
+            Some(ttl) => {
+                if self.ttl_queue.peek_min().map(|(_, next)| ttl < *next).unwrap_or(true) {
+                    self.next_expiry_changed.notify_one();
+                }
+                self.ttl_queue.push(key.to_owned(), ttl);
+            },
+            None => {
+                self.ttl_queue.remove(&key);
+            },
+        }
+        self.handle.insert(key, value);
+        self
+    }
+
+    /// Remove the key along with its scheduled expiry
+    pub fn empty(&mut self, key: K) -> &mut Self {
+        self.ttl_queue.remove(&key);
+        self.last_used.remove(&key);
+        self.handle.empty(key);
+        self
+    }
+}
+
+impl<K: StoreKey> Deref for StoreWriter<K> {
+    type Target = WriteHandle<K, InternalValue>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl<K: StoreKey> DerefMut for StoreWriter<K> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.handle
+    }
+}
+
+/// The StoreWriter behind its lock together with the store's clock, which can be read without
+/// taking the lock so lock-free checks see the same time as the writes.
+pub struct SharedWriter<K: StoreKey = KeyType> {
+    writer: Mutex<StoreWriter<K>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<K: StoreKey> SharedWriter<K> {
+    pub fn lock(&self) -> MutexGuard<'_, StoreWriter<K>> {
+        self.writer.lock()
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, StoreWriter<K>>> {
+        self.writer.try_lock()
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Cap the number of keys the store holds, unbounded when None. Once it is reached a write that
+    /// starts tracking a new key first evicts the key closest to expiring, and is refused with
+    /// ModelError::StoreFull when no key has an expiry to evict. Lowering the cap doesn't evict
+    /// anything until the next new key.
+    pub fn set_max_keys(&self, max_keys: Option<usize>) {
+        self.lock().max_keys = max_keys;
+    }
+}
+
 pub struct Store {}
 
 impl Store {
     /// If the counter is below its associated limit increment it. If/When the limit is reached
     /// then calculate the wait time until the rate limit counter has expired and return
-    /// Err<ModelError> to the api layer
-    pub fn inc_below_limit(
-        writer_m: &Mutex<WriteHandle<KeyType, InternalValue>>,
-        reader: &ReadHandle<KeyType, InternalValue>,
-        key: KeyType,
+    /// Err<ModelError> to the api layer. On success the caller's remaining quota and the time it is
+    /// fully restored are returned, along with whether the charge started tracking the key. `mode`
+    /// picks whether the limit applies to a fixed window starting at the first request, to the last
+    /// `ttl` seconds, or to a window that is pushed back on every allowed request.
+    pub fn inc_below_limit<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
         limit: LimitType,
         ttl: i64,
+        mode: WindowMode,
+    ) -> Result<IncOutcome, ModelError> {
+        Self::inc_by_below_limit(writer_m, reader, key, limit, ttl, 1, mode)
+    }
+
+    /// Decide whether inc_below_limit would allow a request right now without charging it, a
+    /// request that would be throttled gets the same ModelError::PastRateLimit. Nothing is written
+    /// and the writer lock is never taken, `writer_m` only supplies the store's clock.
+    pub fn check<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: &K,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
     ) -> Result<(), ModelError> {
-        if let Some(mut stored_value) = Self::get(reader, &key)? {
-            if stored_value.count < limit {
-                stored_value.count += 1;
-                // re-add the same stored_value to keep ttl
-                Self::upsert_stored_type(writer_m, key, stored_value)?;
-            } else {
-                let time_remaining = stored_value
-                    .ttl
-                    .map(|ttl| ttl.signed_duration_since(Utc::now()).num_seconds())
-                    .unwrap_or_default();
-                return Err(ModelError::PastRateLimit(time_remaining));
+        let params = Params { limit, ttl, cost: 1 };
+        Self::charge(mode.algorithm(), Self::get(reader, key)?, &params, writer_m.now()).map(|_| ())
+    }
+
+    /// Charge `cost` against the counter as long as the charged total stays within the limit,
+    /// otherwise nothing is charged and the wait time until the counter expires is returned the
+    /// same way as inc_below_limit. On success the outcome is returned the same way too. A cost
+    /// below 1 is rejected with ModelError::InvalidCost since it would hand quota back, and a limit
+    /// below 1 with ModelError::InvalidLimit.
+    pub fn inc_by_below_limit<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
+        limit: LimitType,
+        ttl: i64,
+        cost: LimitType,
+        mode: WindowMode,
+    ) -> Result<IncOutcome, ModelError> {
+        if cost < 1 {
+            return Err(ModelError::InvalidCost(cost));
+        }
+        let params = Params { limit, ttl, cost };
+        Self::precheck(reader, &key, mode.algorithm(), &params, writer_m.now())?;
+        let now = writer_m.now();
+        Self::charge_locked(&mut writer_m.lock(), key, mode.algorithm(), &params, now)
+    }
+
+    /// Take one token from the key's bucket. Buckets hold up to `capacity` tokens and refill
+    /// continuously at `refill_per_sec`, so callers can burst up to the capacity and are then held
+    /// to the refill rate. When the bucket is empty ModelError::PastRateLimit carries the seconds
+    /// until the next token. A `refill_per_sec` that isn't positive and finite is
+    /// ModelError::InvalidRate.
+    pub fn take_token<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
+        capacity: LimitType,
+        refill_per_sec: f64,
+    ) -> Result<Quota, ModelError> {
+        if !(refill_per_sec.is_finite() && refill_per_sec > 0.0) {
+            return Err(ModelError::InvalidRate(refill_per_sec));
+        }
+        let algorithm = TokenBucket { refill_per_sec };
+        let params = Params {
+            limit: capacity,
+            ttl: algorithm.seconds_until(capacity as f64),
+            cost: 1,
+        };
+        Self::precheck(reader, &key, &algorithm, &params, writer_m.now())?;
+        Self::inc_with(writer_m, key, &algorithm, &params)
+    }
+
+    /// Pour one request into the key's leaky bucket. Buckets hold up to `capacity` requests and
+    /// drain at `leak_per_sec`, so traffic is shaped to the drain rate with room for `capacity`
+    /// requests queued up. When the bucket is full ModelError::PastRateLimit carries the seconds
+    /// until enough has leaked out. A `leak_per_sec` that isn't positive and finite is
+    /// ModelError::InvalidRate.
+    pub fn leaky_allow<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
+        capacity: LimitType,
+        leak_per_sec: f64,
+    ) -> Result<Quota, ModelError> {
+        if !(leak_per_sec.is_finite() && leak_per_sec > 0.0) {
+            return Err(ModelError::InvalidRate(leak_per_sec));
+        }
+        let algorithm = LeakyBucket { leak_per_sec };
+        let params = Params {
+            limit: capacity,
+            ttl: algorithm.seconds_until(capacity as f64),
+            cost: 1,
+        };
+        Self::precheck(reader, &key, &algorithm, &params, writer_m.now())?;
+        Self::inc_with(writer_m, key, &algorithm, &params)
+    }
+
+    /// Let a request through if it conforms to GCRA pacing: requests are spaced `period_secs` apart
+    /// with up to `burst` of them allowed early. When it doesn't ModelError::PastRateLimit carries
+    /// the seconds until it would. Periods are kept to the millisecond, one that isn't finite or
+    /// rounds down to nothing is ModelError::InvalidRate.
+    pub fn gcra_allow<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
+        period_secs: f64,
+        burst: LimitType,
+    ) -> Result<Quota, ModelError> {
+        if !(period_secs.is_finite() && (period_secs * 1000.0).round() >= 1.0) {
+            return Err(ModelError::InvalidRate(period_secs));
+        }
+        let algorithm = Gcra::every(period_secs);
+        let params = Params {
+            limit: burst,
+            ttl: (period_secs * burst as f64).ceil() as i64,
+            cost: 1,
+        };
+        Self::precheck(reader, &key, &algorithm, &params, writer_m.now())?;
+        Self::inc_with(writer_m, key, &algorithm, &params)
+    }
+
+    /// Charge a request against the key using the given algorithm. The algorithm decides, the
+    /// store only persists the resulting state.
+    pub fn inc_with<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        key: K,
+        algorithm: &dyn Algorithm,
+        params: &Params,
+    ) -> Result<Quota, ModelError> {
+        let now = writer_m.now();
+        Self::charge_locked(&mut writer_m.lock(), key, algorithm, params, now).map(IncOutcome::quota)
+    }
+
+    /// Same as inc_below_limit but it never waits on the writer lock. If the lock is contended
+    /// ModelError::WouldBlock is returned straight away so the caller can decide whether to fail
+    /// open, retry or shed the request.
+    pub fn try_inc_below_limit<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError> {
+        let params = Params { limit, ttl, cost: 1 };
+        Self::precheck(reader, &key, mode.algorithm(), &params, writer_m.now())?;
+        let mut writer = writer_m.try_lock().ok_or(ModelError::WouldBlock)?;
+        Self::charge_locked(&mut writer, key, mode.algorithm(), &params, writer_m.now()).map(IncOutcome::quota)
+    }
+
+    /// Charge one request against each `(key, limit, ttl)` at once, e.g. a global limit together with
+    /// a per route one. Every limit is checked before anything is written and all of the writes are
+    /// published together under one lock, so either every key is charged or, on the first error,
+    /// none are. A key listed twice is charged twice. The quotas are returned in the order given.
+    pub fn inc_all_below_limit<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        items: &[(K, LimitType, i64)],
+        mode: WindowMode,
+    ) -> Result<Vec<Quota>, ModelError> {
+        if items.is_empty() {
+            return Ok(Vec::new());
+        }
+        let algorithm = mode.algorithm();
+        let now = writer_m.now();
+        for (key, limit, ttl) in items {
+            let params = Params { limit: *limit, ttl: *ttl, cost: 1 };
+            Self::precheck(reader, key, algorithm, &params, now)?;
+        }
+        let mut writer = writer_m.lock();
+        let mut charged: Vec<(&K, StoredValue, LimitType)> = Vec::with_capacity(items.len());
+        for (key, limit, ttl) in items {
+            let params = Params { limit: *limit, ttl: *ttl, cost: 1 };
+            // a repeated key builds on its earlier charge, which isn't in the map yet
+            let current = charged
+                .iter()
+                .rev()
+                .find(|(charged_key, ..)| *charged_key == key)
+                .map(|(_, stored_value, _)| stored_value.clone())
+                .or_else(|| writer.get_one(key).map(|v| *v.clone()));
+            charged.push((key, Self::charge(algorithm, current, &params, now)?, *limit));
+        }
+        let quotas = charged
+            .iter()
+            .map(|(_, stored_value, limit)| Quota::of(stored_value, *limit, now))
+            .collect();
+        let new_keys: HashSet<&K> =
+            charged.iter().map(|(key, ..)| *key).filter(|key| !writer.contains_key(key)).collect();
+        Self::make_room(&mut writer, new_keys.len())?;
+        for (key, stored_value, _) in charged {
+            writer.empty(key.to_owned());
+            writer.insert(key.to_owned(), Box::new(stored_value));
+        }
+        writer.refresh();
+        Ok(quotas)
+    }
+
+    /// Every write is refreshed before the writer lock is released so the read snapshot is never
+    /// behind a finished write. A key the snapshot already shows as over the limit can therefore be
+    /// rejected without contending for the lock at all.
+    fn precheck<K: StoreKey>(
+        reader: &ReadHandle<K, InternalValue>,
+        key: &K,
+        algorithm: &dyn Algorithm,
+        params: &Params,
+        now: DateTime<Utc>,
+    ) -> Result<(), ModelError> {
+        if let Some(stored_value) = Self::get(reader, key)? {
+            Self::charge(algorithm, Some(stored_value), params, now)?;
+        }
+        Ok(())
+    }
+
+    /// The read, check and write of a charge all happen while the caller holds the writer lock and
+    /// the current value is read through the write handle, so two concurrent requests can't both
+    /// see room for one more and push the counter past its limit.
+    fn charge_locked<K: StoreKey>(
+        writer: &mut StoreWriter<K>,
+        key: K,
+        algorithm: &dyn Algorithm,
+        params: &Params,
+        now: DateTime<Utc>,
+    ) -> Result<IncOutcome, ModelError> {
+        let current = writer.get_one(&key).map(|v| *v.clone());
+        let created = current.is_none();
+        let stored_value = Self::charge(algorithm, current, params, now)?;
+        if created {
+            Self::make_room(writer, 1)?;
+        }
+        let outcome = IncOutcome::new(created, Quota::of(&stored_value, params.limit, now));
+        // re-add the same stored_value to keep ttl
+        Self::upsert_locked(writer, key, stored_value);
+        Ok(outcome)
+    }
+
+    /// Run the algorithm against the current value of a key, returning the value to store if the
+    /// request is allowed or the wait time until it would be otherwise. A key that isn't tracked
+    /// yet starts from the algorithm's initial state, which is only stored if the request is
+    /// allowed so denied callers don't leave phantom keys behind in the store.
+    pub(crate) fn charge(
+        algorithm: &dyn Algorithm,
+        current: Option<StoredValue>,
+        params: &Params,
+        now: DateTime<Utc>,
+    ) -> Result<StoredValue, ModelError> {
+        if params.limit < 1 {
+            return Err(ModelError::InvalidLimit(params.limit));
+        }
+        let mut stored_value = current.unwrap_or_else(|| algorithm.initial(now, params));
+        match algorithm.check(&mut stored_value, now, params) {
+            Decision::Allow => Ok(stored_value),
+            // a key that is due but not evicted yet is still over its limit, never say retry now
+            Decision::Deny { retry_after } => Err(ModelError::PastRateLimit {
+                retry_after_secs: retry_after.max(1),
+                limit: params.limit,
+            }),
+            Decision::Exhausted => Err(ModelError::LimitExhausted { limit: params.limit }),
+        }
+    }
+
+    /// Unconditionally charge `amount` against the key. This is meant for accounting that happens
+    /// after the work is done (e.g. bytes served) so unlike inc_below_limit it never rejects, the
+    /// caller is instead throttled on its next request once the counter is past the limit. `mode`
+    /// must be the one the key is charged with, sliding windows take the amount into the current
+    /// second's bucket so it leaves the window like any other hit. A charge that would overflow the
+    /// counter is refused with ModelError::Overflow and an amount below 1 with ModelError::InvalidCost.
+    /// The key is read and written under one acquisition of the writer lock so concurrent charges to
+    /// the same key are never lost.
+    pub fn inc_by<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        key: K,
+        amount: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<(), ModelError> {
+        if amount < 1 {
+            return Err(ModelError::InvalidCost(amount));
+        }
+        let now = writer_m.now();
+        let mut writer = writer_m.lock();
+        let current = writer.get_one(&key).map(|v| *v.clone());
+        let created = current.is_none();
+        let mut stored_value = current.unwrap_or_else(|| StoredValue::new(0, ttl, now));
+        let count = stored_value.count.checked_add(amount).ok_or(ModelError::Overflow)?;
+        match mode {
+            WindowMode::Sliding => SlidingWindow::record(&mut stored_value, now, ttl, amount),
+            WindowMode::Fixed | WindowMode::RefreshOnHit => stored_value.count = count,
+        }
+        if created {
+            Self::make_room(&mut writer, 1)?;
+        }
+        Self::upsert_locked(&mut writer, key, stored_value);
+        Ok(())
+    }
+
+    /// Give back a unit reserved by inc_below_limit, e.g. when the request it paid for failed. The
+    /// count never drops below zero and the ttl is kept.
+    pub fn release<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
+    ) -> Result<(), ModelError> {
+        Self::refund(writer_m, reader, key, 1)
+    }
+
+    /// Hand `amount` units back to the key, e.g. when the work a charge paid for failed on our side.
+    /// The count never drops below zero and the ttl is kept. Sliding windows give the units back
+    /// from their newest buckets, token buckets get the tokens back and leaky buckets are drained by
+    /// the amount. A missing key is
+    /// ModelError::NotFound and an amount below 1 ModelError::InvalidCost.
+    pub fn refund<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: K,
+        amount: LimitType,
+    ) -> Result<(), ModelError> {
+        if amount < 1 {
+            return Err(ModelError::InvalidCost(amount));
+        }
+        Self::get(reader, &key)?.ok_or(ModelError::NotFound)?;
+        // re-read under the lock so a charge landing in between isn't lost
+        let mut writer = writer_m.lock();
+        let mut stored_value = writer.get_one(&key).map(|v| *v.clone()).ok_or(ModelError::NotFound)?;
+        stored_value.count = stored_value.count.saturating_sub(amount).max(0);
+        let mut left = amount;
+        while let Some((_, units)) = stored_value.buckets.last_mut().filter(|_| left > 0) {
+            let taken = (*units).min(left);
+            *units -= taken;
+            left -= taken;
+            if *units == 0 {
+                stored_value.buckets.pop();
             }
-        } else {
-            Self::insert(writer_m, &key, 1_i64, ttl)?;
         }
+        if stored_value.last_refill.is_some() {
+            // the next check caps this at the bucket's capacity
+            stored_value.tokens += amount as f64;
+        }
+        if stored_value.last_leak.is_some() {
+            stored_value.level = (stored_value.level - amount as f64).max(0.0);
+        }
+        Self::upsert_locked(&mut writer, key, stored_value);
         Ok(())
     }
 
+    /// Move keys charged with `from` over to `to`, e.g. when a running limiter switches window mode
+    /// or algorithm. Each key keeps the units of the limit it has in use, and its reset time where
+    /// `to` allows it, rather than being handed a fresh budget or losing what is left of it. Keys
+    /// with nothing left in use are removed since a missing key starts with the whole limit, missing
+    /// keys are skipped. All keys are moved under one acquisition of the writer lock and the number
+    /// migrated is returned.
+    pub fn migrate<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        keys: &[K],
+        from: &dyn Algorithm,
+        to: &dyn Algorithm,
+        params: &Params,
+    ) -> usize {
+        let now = writer_m.now();
+        let mut writer = writer_m.lock();
+        let mut migrated = 0;
+        for key in keys {
+            let Some(current) = writer.get_one(key).map(|v| *v.clone()) else {
+                continue;
+            };
+            let usage = from.usage(&current, now, params);
+            if usage.used > 0 {
+                let stored_value = StoredValue {
+                    tag: current.tag,
+                    ..to.with_usage(usage, now, params)
+                };
+                writer.empty(key.to_owned());
+                writer.insert(key.to_owned(), Box::new(stored_value));
+            } else {
+                writer.empty(key.to_owned());
+            }
+            migrated += 1;
+        }
+        writer.refresh();
+        migrated
+    }
+
     /// This upsert function is a workaround since individual elements in EvMaps are not mutable
     /// for the sake of consistencny. Instead of direct mutation remove the element and re-add with
-    /// the same ttl and incremenented count. In order to avoid race conditions the EvMap is then
-    /// refreshed this has a very small chance of conflicting with the loop that reconcilles EvMap
-    /// state which could deadlock. In a production scale project this should probably be owned by
-    /// a single actor.
-    fn upsert_stored_type(
-        writer_m: &Mutex<WriteHandle<KeyType, InternalValue>>,
-        key: KeyType,
-        stored_value: StoredValue,
-    ) -> Result<(), ModelError> {
-        let mut writer = writer_m.lock();
+    /// the same ttl and incremenented count, then refresh before the lock is released. The writer
+    /// mutex is the only lock in the store and it is never held across an await, so this and the
+    /// reconcile loop simply take turns. The one way to deadlock is a refresh waiting on a read
+    /// guard held by the thread that is refreshing, which is why reads clone the value out (see get)
+    /// rather than hand out guards.
+    fn upsert_locked<K: StoreKey>(writer: &mut StoreWriter<K>, key: K, stored_value: StoredValue) {
         writer.empty(key.to_owned());
         writer.insert(key, Box::new(stored_value));
         writer.refresh();
-        Ok(())
     }
 
-    pub fn insert(
-        writer_m: &Mutex<WriteHandle<KeyType, InternalValue>>,
-        key: &KeyType,
+    /// Start tracking a key at `count`, negative counts are rejected with ModelError::InvalidCount
+    pub fn insert<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        key: &K,
         count: LimitType,
         ttl: i64,
     ) -> Result<(), ModelError> {
+        if count < 0 {
+            return Err(ModelError::InvalidCount(count));
+        }
+        let stored_value = StoredValue::new(count, ttl, writer_m.now());
+        Self::insert_locked(&mut writer_m.lock(), key, stored_value)
+    }
+
+    /// Start tracking every `(key, count, ttl)` up front, e.g. the counters of known heavy callers at
+    /// startup so their first request takes the increment path and their expiry is already queued.
+    /// Everything is written and published under one acquisition of the lock. Keys that are
+    /// already tracked, or listed earlier in the batch, are skipped rather than failing the rest, and
+    /// once the store is at its max_keys the remaining entries are dropped instead of evicting
+    /// anything. Returns the number of keys added, a negative count rejects the batch before anything is
+    /// written.
+    pub fn preload<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        entries: &[(K, LimitType, i64)],
+    ) -> Result<usize, ModelError> {
+        if let Some((_, count, _)) = entries.iter().find(|(_, count, _)| *count < 0) {
+            return Err(ModelError::InvalidCount(*count));
+        }
+        let now = writer_m.now();
         let mut writer = writer_m.lock();
-        let current_ttl = Utc::now() + Duration::seconds(ttl);
+        let mut added: HashSet<&K> = HashSet::with_capacity(entries.len());
+        for (key, count, ttl) in entries {
+            if writer.max_keys.is_some_and(|max_keys| writer.len() + added.len() >= max_keys) {
+                // warming up never pushes out keys that are in use
+                break;
+            }
+            if writer.contains_key(key) || !added.insert(key) {
+                continue;
+            }
+            writer.insert(key.to_owned(), Box::new(StoredValue::new(*count, *ttl, now)));
+        }
+        writer.refresh();
+        Ok(added.len())
+    }
+
+    fn insert_locked<K: StoreKey>(
+        writer: &mut StoreWriter<K>,
+        key: &K,
+        stored_value: StoredValue,
+    ) -> Result<(), ModelError> {
         if writer.contains_key(key) {
             return Err(ModelError::AlreadyPresent);
         } else {
-            writer.insert(
-                key.to_owned(),
-                Box::new(StoredValue {
-                    count,
-                    ttl: Some(current_ttl),
-                }),
-            );
+            Self::make_room(writer, 1)?;
+            writer.insert(key.to_owned(), Box::new(stored_value));
+            writer.refresh();
         }
         Ok(())
     }
 
-    pub fn delete(writer_m: &Mutex<WriteHandle<KeyType, InternalValue>>, key: &KeyType) -> Result<(), ModelError> {
+    /// Evict the keys closest to expiring until `new_keys` more fit within max_keys. When too few
+    /// keys have an expiry to make room nothing is evicted and ModelError::StoreFull is returned.
+    /// The evictions are published by the refresh of the write that follows.
+    fn make_room<K: StoreKey>(writer: &mut StoreWriter<K>, new_keys: usize) -> Result<(), ModelError> {
+        let Some(max_keys) = writer.max_keys else {
+            return Ok(());
+        };
+        let excess = (writer.len() + new_keys).saturating_sub(max_keys);
+        if excess > writer.ttl_queue.len() {
+            return Err(ModelError::StoreFull);
+        }
+        for _ in 0..excess {
+            if let Some((key, _)) = writer.ttl_queue.pop_min() {
+                writer.empty(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Approximate memory held by the store, see ESTIMATED_ENTRY_BYTES
+    pub fn estimated_memory_bytes<K: StoreKey>(reader: &ReadHandle<K, InternalValue>) -> usize {
+        reader.len() * ESTIMATED_ENTRY_BYTES
+    }
+
+    /// Make sure that tracking `key` keeps the store within `max_memory_bytes`, applying the policy
+    /// when it would not. Keys that are already tracked don't grow the store and always fit.
+    pub fn reserve_memory<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        key: &K,
+        max_memory_bytes: usize,
+        policy: MemoryPolicy,
+    ) -> Result<(), ModelError> {
+        let needed = Self::estimated_memory_bytes(reader) + ESTIMATED_ENTRY_BYTES;
+        if needed <= max_memory_bytes || reader.contains_key(key) {
+            return Ok(());
+        }
+        match policy {
+            MemoryPolicy::Reject => Err(ModelError::StoreFull),
+            MemoryPolicy::EvictSoonestExpiring => {
+                let excess = (needed - max_memory_bytes).div_ceil(ESTIMATED_ENTRY_BYTES);
+                let mut writer = writer_m.lock();
+                let mut by_expiry: Vec<(K, DateTime<Utc>)> = reader.map_into(|key, values| {
+                    let ttl = values.get_one().and_then(|stored_value| stored_value.ttl);
+                    (key.to_owned(), ttl.unwrap_or(DateTime::<Utc>::MAX_UTC))
+                });
+                if by_expiry.len() < excess {
+                    return Err(ModelError::StoreFull);
+                }
+                by_expiry.sort_by_key(|(_, ttl)| *ttl);
+                for (key, _) in by_expiry.into_iter().take(excess) {
+                    writer.empty(key);
+                }
+                writer.refresh();
+                Ok(())
+            },
+            MemoryPolicy::EvictLeastRecentlyUsed => {
+                let excess = (needed - max_memory_bytes).div_ceil(ESTIMATED_ENTRY_BYTES);
+                let mut writer = writer_m.lock();
+                if writer.last_used.len() < excess {
+                    return Err(ModelError::StoreFull);
+                }
+                for _ in 0..excess {
+                    if let Some((key, _)) = writer.last_used.pop_min() {
+                        writer.empty(key);
+                    }
+                }
+                writer.refresh();
+                Ok(())
+            },
+        }
+    }
+
+    pub fn delete<K: StoreKey>(writer_m: &SharedWriter<K>, key: &K) -> Result<(), ModelError> {
         let mut writer = writer_m.lock();
         if !writer.contains_key(key) {
             return Err(ModelError::NotFound);
         }
         writer.empty(key.to_owned());
+        writer.refresh();
         Ok(())
     }
 
-    pub fn get(reader: &ReadHandle<KeyType, InternalValue>, key: &KeyType) -> Result<Option<StoredValue>, ModelError> {
+    /// Remove every key matching the predicate, along with their ttl entries, under a single
+    /// acquisition of the write lock and return how many were removed.
+    pub fn purge_if<K: StoreKey>(
+        writer_m: &SharedWriter<K>,
+        reader: &ReadHandle<K, InternalValue>,
+        pred: impl Fn(&K, &StoredValue) -> bool,
+    ) -> usize {
+        let mut writer = writer_m.lock();
+        let matching: Vec<Option<K>> = reader.map_into(|key, values| {
+            values
+                .get_one()
+                .filter(|stored_value| pred(key, stored_value))
+                .map(|_| key.to_owned())
+        });
+        let mut purged = 0;
+        for key in matching.into_iter().flatten() {
+            writer.empty(key);
+            purged += 1;
+        }
+        writer.refresh();
+        purged
+    }
+
+    /// Remove every key that starts with `prefix` along with its ttl entry, e.g. every route of a
+    /// scope, and return how many were removed. The matching keys are picked out of the read
+    /// snapshot before the writer lock is taken so only the removals themselves hold up requests.
+    pub fn delete_prefix(
+        writer_m: &SharedWriter,
+        reader: &ReadHandle<KeyType, InternalValue>,
+        prefix: &str,
+    ) -> usize {
+        let matching: Vec<Option<KeyType>> =
+            reader.map_into(|key, _| Some(key.to_owned()).filter(|key| key.starts_with(prefix)));
+        let matching: Vec<KeyType> = matching.into_iter().flatten().collect();
+        if matching.is_empty() {
+            return 0;
+        }
+        let mut writer = writer_m.lock();
+        let mut deleted = 0;
+        for key in matching {
+            // it may have expired since the snapshot was taken
+            if writer.contains_key(&key) {
+                writer.empty(key);
+                deleted += 1;
+            }
+        }
+        writer.refresh();
+        deleted
+    }
+
+    /// Attach or clear the tag on an existing bucket. The count and ttl are left untouched so
+    /// tagging a client never changes its remaining quota, and the bucket is read under the lock so
+    /// a charge landing at the same time isn't lost. Tags longer than MAX_TAG_BYTES are rejected
+    /// with ModelError::TagTooLong.
+    pub fn set_tag<K: StoreKey>(writer_m: &SharedWriter<K>, key: K, tag: Option<String>) -> Result<(), ModelError> {
+        if let Some(len) = tag.as_ref().map(String::len).filter(|len| *len > MAX_TAG_BYTES) {
+            return Err(ModelError::TagTooLong(len));
+        }
+        let mut writer = writer_m.lock();
+        let mut stored_value = writer.get_one(&key).map(|v| *v.clone()).ok_or(ModelError::NotFound)?;
+        stored_value.tag = tag;
+        Self::upsert_locked(&mut writer, key, stored_value);
+        Ok(())
+    }
+
+    /// Whether the key is currently at or over the limit according to the read snapshot. An absent
+    /// key has not been charged yet so it is never reported as limited.
+    pub fn limit_reached<K: StoreKey>(
+        reader: &ReadHandle<K, InternalValue>,
+        key: &K,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+        now: DateTime<Utc>,
+    ) -> bool {
+        Self::used(reader, key, limit, ttl, mode, now) >= limit
+    }
+
+    /// How much of the limit the key has left at `now` according to the read snapshot, without
+    /// charging anything. `ttl` and `mode` must be the ones the key is charged with so units that
+    /// have left a sliding window aren't counted. An absent key has the whole limit left.
+    pub fn remaining<K: StoreKey>(
+        reader: &ReadHandle<K, InternalValue>,
+        key: &K,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+        now: DateTime<Utc>,
+    ) -> Result<LimitType, ModelError> {
+        Ok((limit - Self::used(reader, key, limit, ttl, mode, now)).max(0))
+    }
+
+    /// Units of the limit the key has in use at `now` as its algorithm counts them
+    fn used<K: StoreKey>(
+        reader: &ReadHandle<K, InternalValue>,
+        key: &K,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+        now: DateTime<Utc>,
+    ) -> LimitType {
+        let params = Params { limit, ttl, cost: 1 };
+        reader
+            .get_one(key)
+            .map(|stored_value| mode.algorithm().usage(&stored_value, now, &params).used)
+            .unwrap_or_default()
+    }
+
+    /// Sum the counts of every key per scope, giving a cheap utilization overview per route. Keys
+    /// that were not built by scoped_key have no scope and are left out.
+    pub fn scope_totals(reader: &ReadHandle<KeyType, InternalValue>) -> HashMap<String, LimitType> {
+        let counts: Vec<Option<(String, LimitType)>> = reader.map_into(|key, values| {
+            let scope = key_scope(key)?;
+            values.get_one().map(|stored_value| (scope.to_owned(), stored_value.count))
+        });
+        let mut totals = HashMap::new();
+        for (scope, count) in counts.into_iter().flatten() {
+            *totals.entry(scope).or_insert(0) += count;
+        }
+        totals
+    }
+
+    /// Number of keys in the read snapshot
+    pub fn len<K: StoreKey>(reader: &ReadHandle<K, InternalValue>) -> usize {
+        reader.len()
+    }
+
+    /// Every key in the read snapshot in no particular order. Like all reads this works off the
+    /// published snapshot so listing a large store never holds up writers.
+    pub fn keys<K: StoreKey>(reader: &ReadHandle<K, InternalValue>) -> Vec<K> {
+        reader.map_into(|key, _| key.to_owned())
+    }
+
+    /// Every key that expires before `when`, soonest first. This is computed from the read snapshot
+    /// rather than the ttl queue so it never contends with writers for the lock.
+    pub fn expiring_before<K: StoreKey>(
+        reader: &ReadHandle<K, InternalValue>,
+        when: DateTime<Utc>,
+    ) -> Vec<(K, DateTime<Utc>)> {
+        let expiring: Vec<Option<(K, DateTime<Utc>)>> = reader.map_into(|key, values| {
+            let ttl = values.get_one()?.ttl.filter(|ttl| *ttl < when)?;
+            Some((key.to_owned(), ttl))
+        });
+        let mut expiring: Vec<(K, DateTime<Utc>)> = expiring.into_iter().flatten().collect();
+        expiring.sort_by_key(|(_, ttl)| *ttl);
+        expiring
+    }
+
+    pub fn get<K: StoreKey>(reader: &ReadHandle<K, InternalValue>, key: &K) -> Result<Option<StoredValue>, ModelError> {
         Ok(reader.get_one(key).map(|v| *v.clone()))
     }
+
+    /// Write every key in the read snapshot to `path` as JSON so the counters can outlive the
+    /// process. The entries are wrapped in an envelope carrying SNAPSHOT_VERSION so later versions
+    /// can tell how to read them. The file is written next to `path` and renamed over it so a crash
+    /// mid write never leaves a truncated snapshot behind. Returns the number of keys written.
+    pub fn save_snapshot<K: StoreKey + Serialize>(
+        reader: &ReadHandle<K, InternalValue>,
+        path: &Path,
+    ) -> io::Result<usize> {
+        let entries: Vec<Option<(K, StoredValue)>> =
+            reader.map_into(|key, values| Some((key.to_owned(), *values.get_one()?.clone())));
+        let entries: Vec<(K, StoredValue)> = entries.into_iter().flatten().collect();
+        let envelope = serde_json::json!({ "version": SNAPSHOT_VERSION, "entries": &entries });
+        let partial = path.with_extension("partial");
+        fs::write(&partial, serde_json::to_vec(&envelope)?)?;
+        fs::rename(&partial, path)?;
+        Ok(entries.len())
+    }
+
+    /// Same as save_snapshot but for saving while the store is serving requests. The values are
+    /// read and written `chunk_size` keys at a time, yielding to the runtime between chunks, so a
+    /// large store is streamed to the file in bounded steps rather than copied and serialized in one
+    /// long pause. Keys are listed up front, ones charged for the first time after that are left for
+    /// the next snapshot and ones evicted in the meantime are skipped.
+    pub async fn save_snapshot_in_chunks<K: StoreKey + Serialize>(
+        reader_factory: &ReadHandleFactory<K, InternalValue>,
+        path: &Path,
+        chunk_size: usize,
+    ) -> io::Result<usize> {
+        let reader = reader_factory.handle();
+        let keys = Self::keys(&reader);
+        let partial = path.with_extension("partial");
+        let mut file = io::BufWriter::new(fs::File::create(&partial)?);
+        write!(file, r#"{{"version":{},"entries":["#, SNAPSHOT_VERSION)?;
+        let mut saved = 0;
+        for chunk in keys.chunks(chunk_size.max(1)) {
+            for key in chunk {
+                let Some(stored_value) = reader.get_one(key).map(|v| *v.clone()) else {
+                    continue;
+                };
+                if saved > 0 {
+                    file.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut file, &(key, stored_value))?;
+                saved += 1;
+            }
+            tokio::task::yield_now().await;
+        }
+        file.write_all(b"]}")?;
+        file.flush()?;
+        fs::rename(&partial, path)?;
+        Ok(saved)
+    }
+
+    /// Load a snapshot written by save_snapshot into the store, scheduling the expiry of every key
+    /// again. Snapshots written by older versions are upgraded on the way in and ones written by a
+    /// newer version are refused with io::ErrorKind::InvalidData rather than misread. Keys whose
+    /// ttl passed while the process was down are dropped and a missing file is treated as an empty
+    /// snapshot. The store's max_keys and `max_memory_bytes` apply to the load too, a snapshot
+    /// written under larger limits only has the keys that fit loaded, keeping the ones that expire
+    /// last as they carry the most state. Returns the number of keys loaded.
+    pub fn load_snapshot<K: StoreKey + DeserializeOwned>(
+        writer_m: &SharedWriter<K>,
+        path: &Path,
+        max_memory_bytes: Option<usize>,
+    ) -> io::Result<usize> {
+        let entries: Vec<(K, StoredValue)> = match fs::read(path) {
+            Ok(bytes) => Self::upgrade_snapshot(serde_json::from_slice(&bytes)?)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let now = writer_m.now();
+        let mut writer = writer_m.lock();
+        let mut live: Vec<(K, StoredValue)> = entries
+            .into_iter()
+            .filter(|(_, stored_value)| stored_value.ttl.map(|ttl| ttl > now).unwrap_or(true))
+            .collect();
+        let capacity = [writer.max_keys, max_memory_bytes.map(|bytes| bytes / ESTIMATED_ENTRY_BYTES)]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|capacity| capacity.saturating_sub(writer.len()));
+        if let Some(capacity) = capacity.filter(|capacity| *capacity < live.len()) {
+            // keys that never expire sort last and are kept first
+            live.sort_by_key(|(_, stored_value)| Reverse(stored_value.ttl.unwrap_or(DateTime::<Utc>::MAX_UTC)));
+            live.truncate(capacity);
+        }
+        let loaded = live.len();
+        for (key, stored_value) in live {
+            writer.empty(key.to_owned());
+            writer.insert(key, Box::new(stored_value));
+        }
+        writer.refresh();
+        Ok(loaded)
+    }
     
+    /// Read the entries of a snapshot of any version up to SNAPSHOT_VERSION
+    fn upgrade_snapshot<K: DeserializeOwned>(snapshot: serde_json::Value) -> io::Result<Vec<(K, StoredValue)>> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let (version, entries) = match snapshot {
+            // version 1 had no envelope
+            entries @ serde_json::Value::Array(_) => (1, entries),
+            serde_json::Value::Object(mut envelope) => {
+                let version = envelope
+                    .get("version")
+                    .and_then(serde_json::Value::as_u64)
+                    .ok_or_else(|| invalid("snapshot has no version".to_string()))?;
+                (version, envelope.remove("entries").unwrap_or_default())
+            },
+            _ => return Err(invalid("snapshot is neither an envelope nor a list of entries".to_string())),
+        };
+        if version > SNAPSHOT_VERSION {
+            return Err(invalid(format!(
+                "snapshot version {} is newer than the supported version {}",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        // version 1 entries only had a count and a ttl, every field added since is defaulted when
+        // it is missing (see StoredValue) so they are read the same way as current ones
+        Ok(serde_json::from_value(entries)?)
+    }
+
+    /// Remove up to `max_evictions` elements whose ttl is at or before `now` from the EvMap and
+    /// publish the removals, returning the next ttl still queued, which is at or before `now` when
+    /// the batch ran out first. Every write is refreshed before the lock is released so a pass that
+    /// evicts nothing has nothing to publish and skips the refresh, which waits for readers to move
+    /// off the old map, keeping the lock free for requests.
+    pub fn evict_expired<K: StoreKey>(
+        writer: &mut StoreWriter<K>,
+        now: DateTime<Utc>,
+        max_evictions: usize,
+    ) -> Option<DateTime<Utc>> {
+        let mut evicted = 0;
+        while evicted < max_evictions && matches!(writer.ttl_queue.peek_min(), Some((_, ttl)) if now >= *ttl) {
+            if let Some((key, _)) = writer.ttl_queue.pop_min() {
+                writer.empty(key);
+                evicted += 1;
+            }
+        }
+        if evicted > 0 {
+            writer.refresh();
+        }
+        writer.ttl_queue.peek_min().map(|(_, ttl)| *ttl)
+    }
+
     /// This is the main loop for the in memory store. It will iterate the in memory EvMap removing
     /// elements past their ttl if a ttl has been set. To make this process more efficient rather
-    /// that searching the structure for past TTLs push item ttl onto a queue when added then
-    /// continuously pop items off the queue and remove them from the EvMap.
-    pub async fn init() -> (
-        ReadHandleFactory<KeyType, InternalValue>,
-        Arc<Mutex<WriteHandle<KeyType, InternalValue>>>,
-        JoinHandle<()>,
-    ) {
-        let (read_handle, mut write_handle): (ReadHandle<KeyType, InternalValue>, WriteHandle<KeyType, InternalValue>) =
+    /// that searching the structure for past TTLs the StoreWriter<K> pushes item ttl onto a queue when
+    /// added then this loop pops the expired items off the queue and removes them from the EvMap.
+    /// Between passes it sleeps until the next ttl is due, at most MAX_RECONCILE_INTERVAL, and is
+    /// woken early when a write schedules a sooner expiry. Sending on the returned shutdown sender
+    /// publishes any pending writes and ends the loop, dropping it leaves the loop running.
+    pub async fn init<K: StoreKey>() -> StoreHandles<K> {
+        Self::init_with_clock(Arc::new(SystemClock)).await
+    }
+
+    /// Same as init but every ttl is decided and scheduled against `clock`
+    pub async fn init_with_clock<K: StoreKey>(clock: Arc<dyn Clock>) -> StoreHandles<K> {
+        Self::init_with_batch_size(clock, DEFAULT_EVICT_BATCH_SIZE).await
+    }
+
+    /// Same as init_with_clock but each pass of the loop evicts at most `evict_batch_size` keys
+    /// before releasing the lock and yielding, so an expiry storm on a large store can't starve
+    /// requests of the writer. The rest are evicted by the following passes.
+    pub async fn init_with_batch_size<K: StoreKey>(clock: Arc<dyn Clock>, evict_batch_size: usize) -> StoreHandles<K> {
+        let evict_batch_size = evict_batch_size.max(1);
+        let (read_handle, write_handle): (ReadHandle<K, InternalValue>, WriteHandle<K, InternalValue>) =
             evmap::new();
-        // initiall call used so that we can get accurate pending transactions
-        // https://docs.rs/evmap/latest/evmap/struct.WriteHandle.html#method.pending
-        write_handle.refresh();
-        let writer = Arc::new(Mutex::new(write_handle));
+        let writer = Arc::new(SharedWriter {
+            writer: Mutex::new(StoreWriter::new(write_handle)),
+            clock,
+        });
         let internal_writer = writer.clone();
-        let mut ttl_queue: DoublePriorityQueue<KeyType, DateTime<Utc>> = DoublePriorityQueue::new();
+        let next_expiry_changed = writer.lock().next_expiry_changed.clone();
+        let (shutdown, mut shutdown_requested) = oneshot::channel();
         let timer_handler = task::spawn(async move {
+            let mut shutdown_dropped = false;
             loop {
-                let mut write_handle = internal_writer.lock();
-                for operation in write_handle.pending() {
-                    match operation {
-                        evmap::Operation::Add(k, v) => {
-                            if let Some(ttl) = v.ttl {
-                                ttl_queue.push(k.clone(), ttl);
-                            }
-                        },
-                        evmap::Operation::Empty(k) => {
-                            if ttl_queue.get(k).is_some() {
-                                ttl_queue.remove(k);
-                            }
-                        },
-                        _ => (),
+                let now = internal_writer.now();
+                let next_expiry = {
+                    let mut writer = internal_writer.lock();
+                    let next_expiry = Self::evict_expired(&mut writer, now, evict_batch_size);
+                    #[cfg(test)]
+                    {
+                        writer.reconcile_passes += 1;
+                        // wait for queue to clear for ttl testing
+                        if writer.ttl_queue.is_empty() {
+                            break;
+                        }
                     }
+                    next_expiry
+                };
+                // the batch ran out before the expired keys did, let requests at the lock first
+                if matches!(next_expiry, Some(ttl) if now >= ttl) {
+                    task::yield_now().await;
+                    continue;
                 }
-                let mut next_ttl = ttl_queue.peek_min();
-                while next_ttl.is_some() && Utc::now() > *next_ttl.unwrap().1 {
-                    write_handle.empty(next_ttl.unwrap().0.clone());
-                    ttl_queue.pop_min();
-                    next_ttl = ttl_queue.peek_min();
+                let wait = next_expiry
+                    .map(|ttl| ttl.signed_duration_since(internal_writer.now()).to_std().unwrap_or_default())
+                    .unwrap_or(MAX_RECONCILE_INTERVAL)
+                    .min(MAX_RECONCILE_INTERVAL);
+                tokio::select! {
+                    _ = time::sleep(wait) => {},
+                    _ = next_expiry_changed.notified() => {},
+                    signal = &mut shutdown_requested, if !shutdown_dropped => match signal {
+                        Ok(()) => {
+                            internal_writer.lock().refresh();
+                            break;
+                        },
+                        Err(_) => shutdown_dropped = true,
+                    },
+                }
+            }
+        });
+        (read_handle.factory(), writer, timer_handler, shutdown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn expired_keys_are_evicted_without_spinning() {
+        let (read_handle, write_handle, timer_handler, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "expiring");
+        Store::insert(&write_handle, &key, 1, 1).unwrap();
+        time::timeout(StdDuration::from_secs(3), timer_handler).await.unwrap().unwrap();
+        assert!(Store::get(&reader, &key).unwrap().is_none());
+        // a handful of passes around the insert and the expiry, a spinning loop makes thousands
+        assert!(write_handle.lock().reconcile_passes <= 5);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_the_reconcile_loop() {
+        let (read_handle, write_handle, timer_handler, shutdown) = Store::init().await;
+        let key = scoped_key("get_vault_items", "pending");
+        // a queued ttl keeps the loop alive until it is told to stop
+        Store::insert(&write_handle, &key, 1, 60).unwrap();
+        shutdown.send(()).unwrap();
+        time::timeout(StdDuration::from_secs(3), timer_handler).await.unwrap().unwrap();
+        // the insert was published on the way out and nothing was expired early
+        assert_eq!(Store::get(&read_handle.handle(), &key).unwrap().map(|v| v.count), Some(1));
+    }
+
+    #[tokio::test]
+    async fn check_reports_the_decision_without_charging() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "dry_run");
+        let check = || Store::check(&write_handle, &reader, &key, 2, 60, WindowMode::Fixed);
+        check().unwrap();
+        assert!(Store::get(&reader, &key).unwrap().is_none());
+        for _ in 0..2 {
+            check().unwrap();
+            Store::inc_below_limit(&write_handle, &reader, key.clone(), 2, 60, WindowMode::Fixed).unwrap();
+        }
+        clock.advance(Duration::seconds(20));
+        for _ in 0..3 {
+            assert!(matches!(check(), Err(ModelError::PastRateLimit { retry_after_secs: 40, .. })));
+        }
+        let result = Store::inc_below_limit(&write_handle, &reader, key.clone(), 2, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 40, .. })));
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(2));
+        let result = Store::check(&write_handle, &reader, &key, 0, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::InvalidLimit(0))));
+    }
+
+    #[tokio::test]
+    async fn rejection_carries_limit_and_stored_ttl() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "enriched");
+        for _ in 0..3 {
+            Store::inc_below_limit(&write_handle, &reader, key.clone(), 3, 60, WindowMode::Fixed).unwrap();
+        }
+        clock.advance(Duration::seconds(15));
+        let stored_ttl = Store::get(&reader, &key).unwrap().unwrap().ttl.unwrap();
+        let err = Store::inc_below_limit(&write_handle, &reader, key.clone(), 3, 60, WindowMode::Fixed).unwrap_err();
+        match err {
+            ModelError::PastRateLimit { retry_after_secs, limit } => {
+                assert_eq!(limit, 3);
+                assert_eq!(retry_after_secs, stored_ttl.signed_duration_since(clock.now()).num_seconds());
+                assert_eq!(retry_after_secs, 45);
+            },
+            e => panic!("expected a rate limit rejection, got {:?}", e),
+        }
+        assert_eq!(err.to_string(), "Rate limit of 3 exceeded please wait 45 seconds");
+    }
+
+    #[tokio::test]
+    async fn retry_after_is_at_least_a_second_near_expiry() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "edge");
+        Store::inc_below_limit(&write_handle, &reader, key.clone(), 1, 60, WindowMode::Fixed).unwrap();
+        let retry_after = || {
+            match Store::inc_below_limit(&write_handle, &reader, key.clone(), 1, 60, WindowMode::Fixed) {
+                Err(ModelError::PastRateLimit { retry_after_secs, .. }) => retry_after_secs,
+                other => panic!("expected a rejection, got {:?}", other),
+            }
+        };
+        clock.advance(Duration::milliseconds(59_400));
+        assert_eq!(retry_after(), 1);
+        // due but the main loop hasn't evicted it yet
+        clock.advance(Duration::milliseconds(600));
+        assert_eq!(retry_after(), 1);
+        clock.advance(Duration::seconds(2));
+        assert_eq!(retry_after(), 1);
+    }
+
+    #[tokio::test]
+    async fn non_expiring_key_at_its_limit_is_exhausted() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(Arc::new(ManualClock::new(start))).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "forever");
+        let stored_value = StoredValue {
+            count: 2,
+            ttl: None,
+            ..Default::default()
+        };
+        write_handle.lock().insert(key.clone(), Box::new(stored_value)).refresh();
+        // not a PastRateLimit telling the caller to retry in 0 seconds
+        let result = Store::inc_below_limit(&write_handle, &reader, key.clone(), 2, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::LimitExhausted { limit: 2 })));
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(2));
+        // below the limit it is charged like any other key and still never expires
+        let quota = Store::inc_below_limit(&write_handle, &reader, key.clone(), 5, 60, WindowMode::Fixed).unwrap();
+        assert_eq!(quota.quota().remaining, 2);
+        // with no expiry to report the quota resets as of the store's clock
+        assert_eq!(quota.quota().reset_at, start);
+        assert_eq!(Store::get(&reader, &key).unwrap().and_then(|v| v.ttl), None);
+    }
+
+    #[tokio::test]
+    async fn keys_with_different_ttls_expire_in_order() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let (long, short) = ("add_vault_item:mixed".to_string(), "get_vault_items:mixed".to_string());
+        // the longer window is queued first so ordering can't come from insertion
+        Store::inc_below_limit(&write_handle, &reader, long.clone(), 3, 3600, WindowMode::Fixed).unwrap();
+        Store::inc_below_limit(&write_handle, &reader, short.clone(), 1200, 60, WindowMode::Fixed).unwrap();
+
+        clock.advance(Duration::seconds(59));
+        let next_expiry = Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE);
+        assert_eq!(next_expiry, Some(start + Duration::seconds(60)));
+        clock.advance(Duration::seconds(1));
+        let next_expiry = Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE);
+        assert_eq!(next_expiry, Some(start + Duration::seconds(3600)));
+        assert!(Store::get(&reader, &short).unwrap().is_none());
+        assert!(Store::get(&reader, &long).unwrap().is_some());
+
+        clock.advance(Duration::seconds(3540));
+        assert_eq!(Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE), None);
+        assert!(Store::get(&reader, &long).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn inc_all_below_limit_charges_every_key() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let items = [("global:batch".to_string(), 10, 60), ("add_vault_item:batch".to_string(), 3, 60)];
+        let quotas = Store::inc_all_below_limit(&write_handle, &reader, &items, WindowMode::Fixed).unwrap();
+        assert_eq!(quotas.iter().map(|quota| quota.remaining).collect::<Vec<_>>(), vec![9, 2]);
+        for (key, ..) in &items {
+            assert_eq!(Store::get(&reader, key).unwrap().map(|v| v.count), Some(1));
+        }
+        let quotas = Store::inc_all_below_limit(&write_handle, &reader, &[], WindowMode::Fixed).unwrap();
+        assert!(quotas.is_empty());
+    }
+
+    #[tokio::test]
+    async fn inc_all_below_limit_applies_nothing_when_one_fails() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let (global, route) = ("global:partial".to_string(), "add_vault_item:partial".to_string());
+        Store::insert(&write_handle, &route, 3, 60).unwrap();
+        let items = [(global.clone(), 10, 60), (route.clone(), 3, 60)];
+        let result = Store::inc_all_below_limit(&write_handle, &reader, &items, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        assert!(Store::get(&reader, &global).unwrap().is_none());
+        assert_eq!(Store::get(&reader, &route).unwrap().map(|v| v.count), Some(3));
+        // the second charge of a repeated key is what goes over
+        let items = [(global.clone(), 1, 60), (global.clone(), 1, 60)];
+        let result = Store::inc_all_below_limit(&write_handle, &reader, &items, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        assert!(Store::get(&reader, &global).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn chunked_snapshots_let_charges_through_between_chunks() {
+        let path = std::env::temp_dir().join(format!("rate-limiter-snapshot-chunked-{}.json", std::process::id()));
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for id in 0..1000 {
+            Store::insert(&write_handle, &scoped_key("get_vault_items", &id.to_string()), 1, 60).unwrap();
+        }
+        let saved = AtomicBool::new(false);
+        let save = async {
+            let result = Store::save_snapshot_in_chunks(&read_handle, &path, 100).await;
+            saved.store(true, Ordering::SeqCst);
+            result
+        };
+        let charge = async {
+            let key = scoped_key("add_vault_item", "during-snapshot");
+            let mut charges = 0;
+            while !saved.load(Ordering::SeqCst) {
+                Store::inc_below_limit(&write_handle, &reader, key.clone(), 1_000, 60, WindowMode::Fixed).unwrap();
+                charges += 1;
+                tokio::task::yield_now().await;
+            }
+            charges
+        };
+        let (result, charges) = tokio::join!(save, charge);
+        // every chunk but the last was followed by a charge
+        assert_eq!(result.unwrap(), 1000);
+        assert!(charges >= 9, "{} charges", charges);
+
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        assert_eq!(Store::load_snapshot(&write_handle, &path, None).unwrap(), 1000);
+        fs::remove_file(&path).unwrap();
+        let stored_value = Store::get(&read_handle.handle(), &scoped_key("get_vault_items", "999")).unwrap();
+        assert_eq!(stored_value.map(|v| v.count), Some(1));
+    }
+
+    #[tokio::test]
+    async fn snapshot_loads_stay_within_the_store_limits() {
+        let path = std::env::temp_dir().join(format!("rate-limiter-snapshot-capped-{}.json", std::process::id()));
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for (id, ttl) in [("a", 10), ("b", 40), ("c", 20), ("d", 30)] {
+            Store::insert(&write_handle, &scoped_key("get_vault_items", id), 1, ttl).unwrap();
+        }
+        Store::save_snapshot(&reader, &path).unwrap();
+
+        // the keys expiring last are the ones kept
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        write_handle.set_max_keys(Some(3));
+        assert_eq!(Store::load_snapshot(&write_handle, &path, Some(2 * ESTIMATED_ENTRY_BYTES)).unwrap(), 2);
+        let mut keys: Vec<KeyType> = Store::keys(&read_handle.handle());
+        keys.sort();
+        assert_eq!(keys, ["get_vault_items:b", "get_vault_items:d"]);
+
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        write_handle.set_max_keys(Some(3));
+        assert_eq!(Store::load_snapshot(&write_handle, &path, None).unwrap(), 3);
+        fs::remove_file(&path).unwrap();
+        assert!(Store::get(&read_handle.handle(), &scoped_key("get_vault_items", "a")).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn snapshots_survive_a_restart() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let path = std::env::temp_dir().join(format!("rate-limiter-snapshot-{}.json", std::process::id()));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(Arc::new(ManualClock::new(start))).await;
+        let reader = read_handle.handle();
+        let (kept, expired) = ("add_vault_item:saved".to_string(), "get_vault_items:saved".to_string());
+        for _ in 0..2 {
+            Store::inc_below_limit(&write_handle, &reader, kept.clone(), 3, 60, WindowMode::Fixed).unwrap();
+        }
+        Store::set_tag(&write_handle, kept.clone(), Some("vip".to_string())).unwrap();
+        Store::insert(&write_handle, &expired, 1, 10).unwrap();
+        assert_eq!(Store::save_snapshot(&reader, &path).unwrap(), 2);
+
+        // restarted 20 seconds later
+        let clock = Arc::new(ManualClock::new(start + Duration::seconds(20)));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        assert_eq!(Store::load_snapshot(&write_handle, &path, None).unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+        let stored_value = Store::get(&reader, &kept).unwrap().unwrap();
+        assert_eq!(stored_value.count, 2);
+        assert_eq!(stored_value.tag.as_deref(), Some("vip"));
+        assert_eq!(Quota::of(&stored_value, 3, clock.now()).reset_at - clock.now(), Duration::seconds(40));
+        assert!(Store::get(&reader, &expired).unwrap().is_none());
+        Store::inc_below_limit(&write_handle, &reader, kept.clone(), 3, 60, WindowMode::Fixed).unwrap();
+        let result = Store::inc_below_limit(&write_handle, &reader, kept.clone(), 3, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 40, .. })));
+        // the expiry was scheduled again
+        clock.advance(Duration::seconds(40));
+        assert_eq!(Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE), None);
+        assert!(Store::get(&reader, &kept).unwrap().is_none());
+
+        assert_eq!(Store::load_snapshot(&write_handle, &path, None).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn snapshots_from_older_versions_are_upgraded() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let path = std::env::temp_dir().join(format!("rate-limiter-snapshot-v1-{}.json", std::process::id()));
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "v1");
+        fs::write(&path, r#"[["add_vault_item:v1", {"count": 2, "ttl": "2023-11-14T22:13:40Z"}]]"#).unwrap();
+        assert_eq!(Store::load_snapshot(&write_handle, &path, None).unwrap(), 1);
+        let stored_value = Store::get(&reader, &key).unwrap().unwrap();
+        assert_eq!(stored_value.count, 2);
+        assert_eq!(stored_value.ttl, Some(start + Duration::seconds(20)));
+        assert!(stored_value.tag.is_none() && stored_value.buckets.is_empty() && stored_value.tat.is_none());
+        let result = Store::inc_below_limit(&write_handle, &reader, key.clone(), 2, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 20, .. })));
+
+        // written back in the current version
+        Store::save_snapshot(&reader, &path).unwrap();
+        let saved: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["version"], SNAPSHOT_VERSION);
+        assert_eq!(saved["entries"][0][0], "add_vault_item:v1");
+
+        fs::write(&path, r#"{"version": 99, "entries": []}"#).unwrap();
+        let error = Store::load_snapshot(&write_handle, &path, None).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("version 99"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn increments_and_expiries_interleave_without_hanging() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let mut workers = Vec::new();
+        for worker in 0..4 {
+            let (read_handle, write_handle) = (read_handle.clone(), write_handle.clone());
+            workers.push(task::spawn_blocking(move || {
+                let reader = read_handle.handle();
+                for i in 0..2_000 {
+                    let key = scoped_key("stress", &format!("{}-{}", worker, i % 50));
+                    let _ = Store::inc_below_limit(&write_handle, &reader, key, 5, 1, WindowMode::Fixed);
                 }
-                write_handle.refresh();
-                #[cfg(test)]
-                // wait for queue to clear for ttl testing
-                if ttl_queue.is_empty() {
-                    break;
+            }));
+        }
+        // a second reconcile loop on top of the store's own, with time racing ahead so keys keep expiring
+        let evictor = {
+            let (write_handle, clock) = (write_handle.clone(), clock.clone());
+            task::spawn_blocking(move || {
+                for _ in 0..2_000 {
+                    clock.advance(Duration::milliseconds(250));
+                    let now = clock.now();
+                    Store::evict_expired(&mut write_handle.lock(), now, DEFAULT_EVICT_BATCH_SIZE);
                 }
+            })
+        };
+        workers.push(evictor);
+        let all = async {
+            for worker in workers {
+                worker.await.unwrap();
             }
+        };
+        time::timeout(StdDuration::from_secs(30), all).await.expect("store hung");
+        clock.advance(Duration::seconds(2));
+        Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE);
+        assert_eq!(Store::len(&read_handle.handle()), 0);
+    }
+
+    #[tokio::test]
+    async fn eviction_runs_in_bounded_batches_between_increments() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        for i in 0..100 {
+            Store::insert(&write_handle, &scoped_key("storm", &i.to_string()), 1, 1).unwrap();
+        }
+        clock.advance(Duration::seconds(1));
+        let mut batches = 0;
+        loop {
+            let next_expiry = Store::evict_expired(&mut write_handle.lock(), clock.now(), 10);
+            if !matches!(next_expiry, Some(ttl) if ttl <= clock.now()) {
+                break;
+            }
+            // the batch stopped short, the writer is free for a request before the next one
+            let key = scoped_key("request", &batches.to_string());
+            Store::inc_below_limit(&write_handle, &reader, key, 5, 60, WindowMode::Fixed).unwrap();
+            batches += 1;
+            assert_eq!(Store::len(&reader), 100 - batches * 10 + batches);
+        }
+        // the tenth batch took the last expired keys, only the requests are left
+        assert_eq!(batches, 9);
+        assert_eq!(Store::len(&reader), 9);
+    }
+
+    #[tokio::test]
+    async fn reconcile_loop_evicts_a_storm_over_several_passes() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, timer_handler, _) = Store::init_with_batch_size(clock.clone(), 10).await;
+        for i in 0..100 {
+            Store::insert(&write_handle, &scoped_key("storm", &i.to_string()), 1, 1).unwrap();
+        }
+        clock.advance(Duration::seconds(1));
+        time::timeout(StdDuration::from_secs(3), timer_handler).await.unwrap().unwrap();
+        assert_eq!(Store::len(&read_handle.handle()), 0);
+        assert!(write_handle.lock().reconcile_passes >= 10);
+    }
+
+    #[tokio::test]
+    async fn numeric_keys_are_limited_and_expire() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        // (namespace, account id) rather than a formatted string
+        let (read_handle, write_handle, _, _) = Store::init_with_clock::<(u16, u64)>(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = (7, 42);
+        Store::inc_below_limit(&write_handle, &reader, key, 2, 60, WindowMode::Fixed).unwrap();
+        let quota = Store::inc_below_limit(&write_handle, &reader, key, 2, 60, WindowMode::Fixed).unwrap().quota();
+        assert_eq!(quota.remaining, 0);
+        assert_eq!(quota.reset_at, start + Duration::seconds(60));
+        clock.advance(Duration::seconds(45));
+        let result = Store::inc_below_limit(&write_handle, &reader, key, 2, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 15, .. })));
+        assert!(Store::get(&reader, &(7, 43)).unwrap().is_none());
+        let next_expiry = Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE);
+        assert_eq!(next_expiry, Some(start + Duration::seconds(60)));
+        assert!(Store::get(&reader, &key).unwrap().is_some());
+        clock.advance(Duration::seconds(15));
+        assert_eq!(Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE), None);
+        assert!(Store::get(&reader, &key).unwrap().is_none());
+        let quota = Store::inc_below_limit(&write_handle, &reader, key, 2, 60, WindowMode::Fixed).unwrap().quota();
+        assert_eq!(quota.remaining, 1);
+        assert_eq!(quota.reset_at, start + Duration::seconds(120));
+    }
+
+    #[test]
+    fn client_ip_skips_trusted_hops() {
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        let chain = Some("203.0.113.7, 10.0.0.1");
+        assert_eq!(client_ip(chain, peer, 0), peer);
+        assert_eq!(client_ip(chain, peer, 1), "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(client_ip(chain, peer, 2), "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(client_ip(None, peer, 1), peer);
+    }
+
+    #[test]
+    fn client_ip_ignores_spoofed_leading_entry() {
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        // the client forged the first entry, the single trusted proxy appended its real address
+        let chain = Some("198.51.100.1, 203.0.113.7");
+        assert_eq!(client_ip(chain, peer, 1), "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(client_ip(Some("not-an-ip, 203.0.113.7"), peer, 2), peer);
+    }
+
+    #[tokio::test]
+    async fn zero_limit_rejects_without_creating_key() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "zero");
+        for limit in [0, -1, LimitType::MIN] {
+            let result = Store::inc_below_limit(&write_handle, &reader, key.clone(), limit, 60, WindowMode::Fixed);
+            assert!(matches!(result, Err(ModelError::InvalidLimit(l)) if l == limit));
+        }
+        assert!(Store::get(&reader, &key).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn counts_stop_at_max_instead_of_wrapping() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "max");
+        Store::insert(&write_handle, &key, LimitType::MAX - 1, 60).unwrap();
+        let quota = Store::inc_below_limit(&write_handle, &reader, key.clone(), LimitType::MAX, 60, WindowMode::Fixed)
+            .unwrap()
+            .quota();
+        assert_eq!(quota.remaining, 0);
+        // a key at MAX is simply over its limit, only the unconditional charge can overflow
+        let result = Store::inc_below_limit(&write_handle, &reader, key.clone(), LimitType::MAX, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        let result = Store::inc_by(&write_handle, key.clone(), 1, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::Overflow)));
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(LimitType::MAX));
+    }
+
+    #[tokio::test]
+    async fn keys_near_max_are_throttled_not_overflowed() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for mode in [WindowMode::Fixed, WindowMode::RefreshOnHit, WindowMode::Sliding] {
+            let key = scoped_key("get_vault_items", &format!("near-max-{:?}", mode));
+            Store::inc_by(&write_handle, key.clone(), 1, 60, mode).unwrap();
+            Store::inc_by(&write_handle, key.clone(), LimitType::MAX - 1, 60, mode).unwrap();
+            let result = Store::inc_by_below_limit(&write_handle, &reader, key.clone(), 5, 60, 3, mode);
+            assert!(matches!(result, Err(ModelError::PastRateLimit { limit: 5, .. })), "{:?}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn negative_counts_are_not_inserted() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let key = scoped_key("add_vault_item", "negative");
+        assert!(matches!(Store::insert(&write_handle, &key, -1, 60), Err(ModelError::InvalidCount(-1))));
+        assert!(Store::get(&read_handle.handle(), &key).unwrap().is_none());
+        Store::insert(&write_handle, &key, 0, 60).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_increments_never_exceed_limit() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let key = scoped_key("get_vault_items", "hammered");
+        let limit = 25;
+        // plain threads so the requests really race each other for the writer lock
+        let allowed: usize = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let reader = read_handle.handle();
+                        let mode = WindowMode::Fixed;
+                        let charge = || Store::inc_below_limit(&write_handle, &reader, key.clone(), limit, 60, mode);
+                        (0..20).filter(|_| charge().is_ok()).count()
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).sum()
         });
-        (read_handle.factory(), writer, timer_handler)
+        assert_eq!(allowed, limit as usize);
+        let stored_value = Store::get(&read_handle.handle(), &key).unwrap().unwrap();
+        assert_eq!(stored_value.count, limit);
+    }
+
+    #[tokio::test]
+    async fn over_limit_rejection_keeps_existing_bucket() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "over");
+        Store::insert(&write_handle, &key, 1, 60).unwrap();
+        let result = Store::inc_below_limit(&write_handle, &reader, key.clone(), 1, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(1));
+    }
+
+    #[tokio::test]
+    async fn sliding_mode_stores_hits_in_buckets() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "sliding");
+        for _ in 0..2 {
+            Store::inc_below_limit(&write_handle, &reader, key.clone(), 2, 60, WindowMode::Sliding).unwrap();
+        }
+        let result = Store::inc_below_limit(&write_handle, &reader, key.clone(), 2, 60, WindowMode::Sliding);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        let stored_value = Store::get(&reader, &key).unwrap().unwrap();
+        assert_eq!(stored_value.count, 2);
+        assert_eq!(stored_value.buckets.iter().map(|(_, units)| units).sum::<LimitType>(), 2);
+    }
+
+    #[tokio::test]
+    async fn refresh_on_hit_keeps_moving_expiry_forward() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "active");
+        for hit in 0..3 {
+            Store::inc_below_limit(&write_handle, &reader, key.clone(), 10, 60, WindowMode::RefreshOnHit).unwrap();
+            let ttl = Store::get(&reader, &key).unwrap().and_then(|v| v.ttl);
+            assert_eq!(ttl, Some(start + Duration::seconds(60 + 30 * hit)));
+            // the queue holds exactly one entry for the key, at the new expiry
+            let writer = write_handle.lock();
+            assert_eq!(writer.ttl_queue.len(), 1);
+            assert_eq!(writer.ttl_queue.get_priority(&key).copied(), ttl);
+            drop(writer);
+            clock.advance(Duration::seconds(30));
+        }
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(3));
+    }
+
+    #[tokio::test]
+    async fn take_token_drains_bucket_then_throttles() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "bucket");
+        for _ in 0..3 {
+            Store::take_token(&write_handle, &reader, key.clone(), 3, 0.5).unwrap();
+        }
+        let result = Store::take_token(&write_handle, &reader, key.clone(), 3, 0.5);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 2, .. })));
+        let stored_value = Store::get(&reader, &key).unwrap().unwrap();
+        assert_eq!(stored_value.count, 3);
+        assert!(stored_value.tokens < 1.0);
+    }
+
+    #[tokio::test]
+    async fn rate_based_limiters_reject_invalid_rates() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "rated");
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let result = Store::take_token(&write_handle, &reader, key.clone(), 3, rate);
+            assert!(matches!(result, Err(ModelError::InvalidRate(_))), "refill of {}", rate);
+            let result = Store::leaky_allow(&write_handle, &reader, key.clone(), 3, rate);
+            assert!(matches!(result, Err(ModelError::InvalidRate(_))), "leak of {}", rate);
+        }
+        // a period under half a millisecond would pace nothing at all
+        for period in [0.0, 0.0004, -2.0, f64::NAN, f64::INFINITY] {
+            let result = Store::gcra_allow(&write_handle, &reader, key.clone(), period, 2);
+            assert!(matches!(result, Err(ModelError::InvalidRate(_))), "period of {}", period);
+        }
+        assert!(Store::get(&reader, &key).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn leaky_allow_fills_then_drains() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "shaped");
+        for remaining in [1, 0] {
+            let quota = Store::leaky_allow(&write_handle, &reader, key.clone(), 2, 1.0).unwrap();
+            assert_eq!(quota.remaining, remaining);
+        }
+        let result = Store::leaky_allow(&write_handle, &reader, key.clone(), 2, 1.0);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 1, limit: 2 })));
+        assert_eq!(Store::get(&reader, &key).unwrap().and_then(|v| v.ttl), Some(start + Duration::seconds(2)));
+        clock.advance(Duration::seconds(1));
+        let quota = Store::leaky_allow(&write_handle, &reader, key.clone(), 2, 1.0).unwrap();
+        assert_eq!(quota.remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn gcra_allow_paces_requests() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "paced");
+        for remaining in [1, 0] {
+            let quota = Store::gcra_allow(&write_handle, &reader, key.clone(), 2.0, 2).unwrap();
+            assert_eq!(quota.remaining, remaining);
+        }
+        let result = Store::gcra_allow(&write_handle, &reader, key.clone(), 2.0, 2);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 2, .. })));
+        assert_eq!(Store::get(&reader, &key).unwrap().and_then(|v| v.tat), Some(start + Duration::seconds(4)));
+        clock.advance(Duration::seconds(2));
+        Store::gcra_allow(&write_handle, &reader, key.clone(), 2.0, 2).unwrap();
+    }
+
+    #[tokio::test]
+    async fn inc_below_limit_reports_remaining_quota() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "quota");
+        let first = Store::inc_below_limit(&write_handle, &reader, key.clone(), 3, 60, WindowMode::Fixed).unwrap();
+        let IncOutcome::Created { remaining: 2, reset_at } = first else {
+            panic!("expected the first charge to create the key, got {:?}", first);
+        };
+        let first = first.quota();
+        assert_eq!(first.reset_at, reset_at);
+        assert_eq!(Some(first.reset_at), Store::get(&reader, &key).unwrap().and_then(|v| v.ttl));
+        for remaining in [1, 0] {
+            let quota = Store::inc_below_limit(&write_handle, &reader, key.clone(), 3, 60, WindowMode::Fixed).unwrap();
+            assert!(matches!(quota, IncOutcome::Incremented { .. }));
+            let quota = quota.quota();
+            assert_eq!(quota.remaining, remaining);
+            // the fixed window resets when it started, not when it was last charged
+            assert_eq!(quota.reset_at, first.reset_at);
+        }
+    }
+
+    #[tokio::test]
+    async fn first_charge_after_expiry_creates_the_key_again() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "cold");
+        let charge = || Store::inc_below_limit(&write_handle, &reader, key.clone(), 3, 60, WindowMode::Fixed).unwrap();
+        assert_eq!(charge(), IncOutcome::Created {
+            remaining: 2,
+            reset_at: start + Duration::seconds(60)
+        });
+        assert_eq!(charge(), IncOutcome::Incremented {
+            remaining: 1,
+            reset_at: start + Duration::seconds(60)
+        });
+        clock.advance(Duration::seconds(60));
+        Store::evict_expired(&mut write_handle.lock(), clock.now(), DEFAULT_EVICT_BATCH_SIZE);
+        assert_eq!(charge(), IncOutcome::Created {
+            remaining: 2,
+            reset_at: start + Duration::seconds(120)
+        });
+    }
+
+    #[tokio::test]
+    async fn weighted_charge_can_use_up_the_exact_remaining_budget() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "export");
+        Store::inc_by_below_limit(&write_handle, &reader, key.clone(), 10, 60, 4, WindowMode::Fixed).unwrap();
+        let quota =
+            Store::inc_by_below_limit(&write_handle, &reader, key.clone(), 10, 60, 6, WindowMode::Fixed).unwrap();
+        assert_eq!(quota.quota().remaining, 0);
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(10));
+    }
+
+    #[tokio::test]
+    async fn weighted_charge_that_overshoots_is_not_charged() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("add_vault_item", "overshoot");
+        Store::inc_by_below_limit(&write_handle, &reader, key.clone(), 10, 60, 4, WindowMode::Fixed).unwrap();
+        let result = Store::inc_by_below_limit(&write_handle, &reader, key.clone(), 10, 60, 7, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(4));
+        for cost in [0, -3] {
+            let result =
+                Store::inc_by_below_limit(&write_handle, &reader, key.clone(), 10, 60, cost, WindowMode::Fixed);
+            assert!(matches!(result, Err(ModelError::InvalidCost(c)) if c == cost));
+        }
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(4));
+    }
+
+    #[tokio::test]
+    async fn try_inc_below_limit_reports_contention() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "contended");
+        let held = write_handle.lock();
+        let result = Store::try_inc_below_limit(&write_handle, &reader, key.clone(), 5, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::WouldBlock)));
+        drop(held);
+        Store::try_inc_below_limit(&write_handle, &reader, key.clone(), 5, 60, WindowMode::Fixed).unwrap();
+        Store::try_inc_below_limit(&write_handle, &reader, key.clone(), 5, 60, WindowMode::Fixed).unwrap();
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(2));
+    }
+
+    #[tokio::test]
+    async fn limit_reached_tracks_count() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("put_vault_items", "reached");
+        let reached = |limit| Store::limit_reached(&reader, &key, limit, 60, WindowMode::Fixed, write_handle.now());
+        assert!(!reached(2));
+        Store::insert(&write_handle, &key, 1, 60).unwrap();
+        assert!(!reached(2));
+        Store::inc_below_limit(&write_handle, &reader, key.clone(), 2, 60, WindowMode::Fixed).unwrap();
+        assert!(reached(2));
+        assert!(reached(1));
+        assert!(!reached(3));
+    }
+
+    #[tokio::test]
+    async fn inc_by_charges_amount() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "download");
+        Store::inc_by(&write_handle, key.clone(), 4, 60, WindowMode::Fixed).unwrap();
+        Store::inc_by(&write_handle, key.clone(), 3, 60, WindowMode::Fixed).unwrap();
+        for amount in [0, -2] {
+            let result = Store::inc_by(&write_handle, key.clone(), amount, 60, WindowMode::Fixed);
+            assert!(matches!(result, Err(ModelError::InvalidCost(a)) if a == amount));
+        }
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(7));
+        // post-hoc charges may push the counter past the limit, the next request is rejected
+        let result = Store::inc_below_limit(&write_handle, &reader, key, 5, 60, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+    }
+
+    #[tokio::test]
+    async fn inc_by_counts_toward_a_sliding_window() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "sliding_download");
+        let charge = || Store::inc_below_limit(&write_handle, &reader, key.clone(), 5, 10, WindowMode::Sliding);
+        charge().unwrap();
+        clock.advance(Duration::seconds(4));
+        Store::inc_by(&write_handle, key.clone(), 3, 10, WindowMode::Sliding).unwrap();
+        // the peeks and the check agree on what is left
+        assert_eq!(Store::remaining(&reader, &key, 5, 10, WindowMode::Sliding, clock.now()).unwrap(), 1);
+        assert!(!Store::limit_reached(&reader, &key, 5, 10, WindowMode::Sliding, clock.now()));
+        assert_eq!(charge().unwrap().quota().remaining, 0);
+        assert!(Store::limit_reached(&reader, &key, 5, 10, WindowMode::Sliding, clock.now()));
+        assert!(matches!(charge(), Err(ModelError::PastRateLimit { retry_after_secs: 7, .. })));
+        // the first hit leaves the window before the post-hoc units do, the peeks see it go too
+        clock.advance(Duration::seconds(7));
+        assert_eq!(Store::remaining(&reader, &key, 5, 10, WindowMode::Sliding, clock.now()).unwrap(), 1);
+        assert!(!Store::limit_reached(&reader, &key, 5, 10, WindowMode::Sliding, clock.now()));
+        assert_eq!(charge().unwrap().quota().remaining, 0);
+        clock.advance(Duration::seconds(4));
+        assert_eq!(charge().unwrap().quota().remaining, 3);
+
+        // a key first charged after the fact is tracked in buckets too
+        let fresh = scoped_key("get_vault_items", "sliding_fresh");
+        Store::inc_by(&write_handle, fresh.clone(), 5, 10, WindowMode::Sliding).unwrap();
+        let result = Store::inc_below_limit(&write_handle, &reader, fresh, 5, 10, WindowMode::Sliding);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+    }
+
+    #[tokio::test]
+    async fn concurrent_inc_by_loses_no_charges() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let key = scoped_key("get_vault_items", "concurrent_download");
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (write_handle, key) = (write_handle.clone(), key.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        Store::inc_by(&write_handle, key.clone(), 2, 60, WindowMode::Fixed).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let reader = read_handle.handle();
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(800));
+    }
+
+    #[tokio::test]
+    async fn migrating_mid_window_keeps_the_remaining_budget() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let (to_sliding, to_bucket, idle) = (
+            scoped_key("get_vault_items", "to_sliding"),
+            scoped_key("get_vault_items", "to_bucket"),
+            scoped_key("get_vault_items", "idle"),
+        );
+        for key in [&to_sliding, &to_bucket] {
+            for _ in 0..3 {
+                Store::inc_below_limit(&write_handle, &reader, key.clone(), 5, 60, WindowMode::Fixed).unwrap();
+            }
+        }
+        Store::insert(&write_handle, &idle, 0, 60).unwrap();
+        clock.advance(Duration::seconds(20));
+        let params = Params {
+            limit: 5,
+            ttl: 60,
+            cost: 1,
+        };
+        let keys = [to_sliding.clone(), idle.clone(), scoped_key("get_vault_items", "missing")];
+        assert_eq!(Store::migrate(&write_handle, &keys, &FixedWindow, &SlidingWindow, &params), 2);
+        // nothing was in use so the key is simply dropped
+        assert!(Store::get(&reader, &idle).unwrap().is_none());
+        // the sliding window keeps the two units left and gives them all back when the fixed one would have
+        for remaining in [1, 0] {
+            let quota = Store::inc_below_limit(&write_handle, &reader, to_sliding.clone(), 5, 60, WindowMode::Sliding);
+            assert_eq!(quota.unwrap().quota().remaining, remaining);
+        }
+        let result = Store::inc_below_limit(&write_handle, &reader, to_sliding.clone(), 5, 60, WindowMode::Sliding);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { retry_after_secs: 40, .. })));
+
+        let bucket = TokenBucket { refill_per_sec: 0.1 };
+        assert_eq!(Store::migrate(&write_handle, std::slice::from_ref(&to_bucket), &FixedWindow, &bucket, &params), 1);
+        for _ in 0..2 {
+            Store::inc_with(&write_handle, to_bucket.clone(), &bucket, &params).unwrap();
+        }
+        let result = Store::inc_with(&write_handle, to_bucket.clone(), &bucket, &params);
+        assert!(matches!(result, Err(ModelError::PastRateLimit { .. })));
+    }
+
+    #[tokio::test]
+    async fn release_returns_reserved_unit() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("put_vault_items", "release");
+        assert!(matches!(Store::release(&write_handle, &reader, key.clone()), Err(ModelError::NotFound)));
+        Store::insert(&write_handle, &key, 1, 60).unwrap();
+        Store::release(&write_handle, &reader, key.clone()).unwrap();
+        Store::release(&write_handle, &reader, key.clone()).unwrap();
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(0));
+    }
+
+    #[tokio::test]
+    async fn refund_restores_charged_quota() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("put_vault_items", "refund");
+        let result = Store::refund(&write_handle, &reader, key.clone(), 1);
+        assert!(matches!(result, Err(ModelError::NotFound)));
+        let before =
+            Store::inc_below_limit(&write_handle, &reader, key.clone(), 5, 60, WindowMode::Fixed).unwrap().quota();
+        Store::inc_by_below_limit(&write_handle, &reader, key.clone(), 5, 60, 3, WindowMode::Fixed).unwrap();
+        Store::refund(&write_handle, &reader, key.clone(), 3).unwrap();
+        let stored_value = Store::get(&reader, &key).unwrap().unwrap();
+        assert_eq!(Quota::of(&stored_value, 5, write_handle.now()), before);
+        // refunding more than was charged stops at zero
+        Store::refund(&write_handle, &reader, key.clone(), 10).unwrap();
+        Store::refund(&write_handle, &reader, key.clone(), 1).unwrap();
+        let stored_value = Store::get(&reader, &key).unwrap().unwrap();
+        assert_eq!(stored_value.count, 0);
+        assert_eq!(Quota::of(&stored_value, 5, write_handle.now()).reset_at, before.reset_at);
+        let result = Store::refund(&write_handle, &reader, key.clone(), 0);
+        assert!(matches!(result, Err(ModelError::InvalidCost(0))));
+
+        let sliding = scoped_key("put_vault_items", "refund_sliding");
+        for _ in 0..2 {
+            Store::inc_below_limit(&write_handle, &reader, sliding.clone(), 2, 60, WindowMode::Sliding).unwrap();
+        }
+        Store::refund(&write_handle, &reader, sliding.clone(), 1).unwrap();
+        Store::inc_below_limit(&write_handle, &reader, sliding.clone(), 2, 60, WindowMode::Sliding).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remaining_peeks_without_charging() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "peek");
+        assert_eq!(Store::remaining(&reader, &key, 5, 60, WindowMode::Fixed, write_handle.now()).unwrap(), 5);
+        Store::inc_by(&write_handle, key.clone(), 2, 60, WindowMode::Fixed).unwrap();
+        for _ in 0..3 {
+            assert_eq!(Store::remaining(&reader, &key, 5, 60, WindowMode::Fixed, write_handle.now()).unwrap(), 3);
+        }
+        assert_eq!(Store::remaining(&reader, &key, 1, 60, WindowMode::Fixed, write_handle.now()).unwrap(), 0);
+        assert_eq!(Store::get(&reader, &key).unwrap().map(|v| v.count), Some(2));
+    }
+
+    #[tokio::test]
+    async fn purge_if_removes_only_matching_keys() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for (key, count) in [("abandoned_a", 1), ("abandoned_b", 2), ("active_a", 5), ("active_b", 9)] {
+            Store::insert(&write_handle, &key.to_string(), count, 60).unwrap();
+        }
+        let purged = Store::purge_if(&write_handle, &reader, |_, stored_value| stored_value.count < 3);
+        assert_eq!(purged, 2);
+        assert!(Store::get(&reader, &"abandoned_a".to_string()).unwrap().is_none());
+        assert!(Store::get(&reader, &"abandoned_b".to_string()).unwrap().is_none());
+        assert_eq!(Store::get(&reader, &"active_a".to_string()).unwrap().map(|v| v.count), Some(5));
+        assert_eq!(Store::get(&reader, &"active_b".to_string()).unwrap().map(|v| v.count), Some(9));
+    }
+
+    #[tokio::test]
+    async fn preload_seeds_keys_and_skips_duplicates() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        let existing = scoped_key("add_vault_item", "existing");
+        Store::insert(&write_handle, &existing, 2, 60).unwrap();
+        let entries = [
+            (scoped_key("add_vault_item", "top"), 0, 60),
+            (existing.clone(), 0, 60),
+            (scoped_key("get_vault_items", "top"), 0, 30),
+            (scoped_key("add_vault_item", "top"), 1, 90),
+        ];
+        assert_eq!(Store::preload(&write_handle, &entries).unwrap(), 2);
+        let stored = |key: &KeyType| Store::get(&reader, key).unwrap().map(|v| (v.count, v.ttl.unwrap()));
+        assert_eq!(stored(&entries[0].0), Some((0, start + Duration::seconds(60))));
+        assert_eq!(stored(&entries[2].0), Some((0, start + Duration::seconds(30))));
+        // neither the tracked key nor the repeat in the batch were overwritten
+        assert_eq!(stored(&existing), Some((2, start + Duration::seconds(60))));
+        // the expiries are queued straight away
+        assert_eq!(write_handle.lock().ttl_queue.len(), 3);
+        let quota = Store::inc_below_limit(&write_handle, &reader, entries[0].0.clone(), 3, 60, WindowMode::Fixed);
+        assert!(matches!(quota, Ok(IncOutcome::Incremented { remaining: 2, .. })));
+
+        let invalid = [(scoped_key("add_vault_item", "new"), 0, 60), (existing, -1, 60)];
+        assert!(matches!(Store::preload(&write_handle, &invalid), Err(ModelError::InvalidCount(-1))));
+        assert_eq!(Store::len(&reader), 3);
+    }
+
+    #[tokio::test]
+    async fn max_keys_holds_under_a_flood_of_new_keys() {
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let clock = Arc::new(ManualClock::new(start));
+        let (read_handle, write_handle, _, _) = Store::init_with_clock(clock.clone()).await;
+        let reader = read_handle.handle();
+        write_handle.set_max_keys(Some(10));
+        for i in 0..100 {
+            let key = scoped_key("get_vault_items", &i.to_string());
+            Store::inc_below_limit(&write_handle, &reader, key, 5, 60, WindowMode::Fixed).unwrap();
+            Store::insert(&write_handle, &scoped_key("add_vault_item", &i.to_string()), 1, 60).unwrap();
+            assert!(Store::len(&reader) <= 10);
+            assert!(write_handle.lock().ttl_queue.len() <= 10);
+        }
+        assert_eq!(Store::len(&reader), 10);
+        // a tracked key is charged without evicting anything
+        let tracked = scoped_key("add_vault_item", "99");
+        Store::inc_below_limit(&write_handle, &reader, tracked.clone(), 5, 60, WindowMode::Fixed).unwrap();
+        assert_eq!(Store::get(&reader, &tracked).unwrap().map(|v| v.count), Some(2));
+        assert_eq!(Store::len(&reader), 10);
+    }
+
+    #[tokio::test]
+    async fn max_keys_evicts_the_soonest_expiring_key() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        write_handle.set_max_keys(Some(2));
+        let (late, soon, new) = ("late".to_string(), "soon".to_string(), "new".to_string());
+        Store::insert(&write_handle, &late, 1, 600).unwrap();
+        Store::insert(&write_handle, &soon, 1, 60).unwrap();
+        Store::inc_below_limit(&write_handle, &reader, new.clone(), 5, 300, WindowMode::Fixed).unwrap();
+        assert!(Store::get(&reader, &soon).unwrap().is_none());
+        assert!(Store::get(&reader, &late).unwrap().is_some());
+        assert!(Store::get(&reader, &new).unwrap().is_some());
+        assert!(write_handle.lock().ttl_queue.get_priority(&soon).is_none());
+
+        // nothing with an expiry left to make room with
+        Store::purge_if(&write_handle, &reader, |_, _| true);
+        for key in [&late, &soon] {
+            write_handle.lock().insert(key.clone(), Box::new(StoredValue::default())).refresh();
+        }
+        let result = Store::inc_below_limit(&write_handle, &reader, new, 5, 300, WindowMode::Fixed);
+        assert!(matches!(result, Err(ModelError::StoreFull)));
+        assert_eq!(Store::len(&reader), 2);
+    }
+
+    #[tokio::test]
+    async fn delete_prefix_removes_only_matching_keys() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for key in ["add_vault_item:a", "add_vault_item:b", "put_vault_items:a", "add_vault_items:c"] {
+            Store::insert(&write_handle, &key.to_string(), 1, 60).unwrap();
+        }
+        assert_eq!(Store::delete_prefix(&write_handle, &reader, "add_vault_item:"), 2);
+        let mut keys = Store::keys(&reader);
+        keys.sort();
+        assert_eq!(keys, vec!["add_vault_items:c".to_string(), "put_vault_items:a".to_string()]);
+        // their expiries went with them
+        assert_eq!(write_handle.lock().ttl_queue.len(), 2);
+
+        assert_eq!(Store::delete_prefix(&write_handle, &reader, "get_vault_items:"), 0);
+        assert_eq!(Store::len(&reader), 2);
+    }
+
+    #[tokio::test]
+    async fn scope_totals_group_and_sum_by_scope() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for (scope, id, count) in [
+            ("get_vault_items", "a", 3),
+            ("get_vault_items", "b", 4),
+            ("get_vault_items", "c:d", 5),
+            ("add_vault_item", "a", 1),
+            ("add_vault_item", "b", 2),
+        ] {
+            Store::insert(&write_handle, &scoped_key(scope, id), count, 60).unwrap();
+        }
+        Store::insert(&write_handle, &"unscoped".to_string(), 7, 60).unwrap();
+        let totals = Store::scope_totals(&reader);
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals["get_vault_items"], 12);
+        assert_eq!(totals["add_vault_item"], 3);
+    }
+
+    #[tokio::test]
+    async fn len_and_keys_follow_the_store() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        assert_eq!(Store::len(&reader), 0);
+        for id in ["a", "b", "c"] {
+            Store::insert(&write_handle, &scoped_key("get_vault_items", id), 1, 60).unwrap();
+        }
+        Store::delete(&write_handle, &scoped_key("get_vault_items", "b")).unwrap();
+        assert_eq!(Store::len(&reader), 2);
+        let mut keys = Store::keys(&reader);
+        keys.sort();
+        assert_eq!(keys, vec!["get_vault_items:a".to_string(), "get_vault_items:c".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn expiring_before_lists_keys_in_expiry_order() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for (key, ttl) in [("late", 120), ("soon", 10), ("never_listed", 3600), ("sooner", 5)] {
+            Store::insert(&write_handle, &key.to_string(), 1, ttl).unwrap();
+        }
+        let expiring = Store::expiring_before(&reader, Utc::now() + Duration::seconds(300));
+        let keys: Vec<&str> = expiring.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["sooner", "soon", "late"]);
+        assert!(Store::expiring_before(&reader, Utc::now()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_budget_rejects_new_keys() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for key in ["a", "b", "c"] {
+            Store::insert(&write_handle, &key.to_string(), 1, 60).unwrap();
+        }
+        let budget = 3 * ESTIMATED_ENTRY_BYTES;
+        assert_eq!(Store::estimated_memory_bytes(&reader), budget);
+        let result = Store::reserve_memory(&write_handle, &reader, &"d".to_string(), budget, MemoryPolicy::Reject);
+        assert!(matches!(result, Err(ModelError::StoreFull)));
+        // tracked keys keep being served once the budget is reached
+        Store::reserve_memory(&write_handle, &reader, &"a".to_string(), budget, MemoryPolicy::Reject).unwrap();
+        let roomier = budget + ESTIMATED_ENTRY_BYTES;
+        Store::reserve_memory(&write_handle, &reader, &"d".to_string(), roomier, MemoryPolicy::Reject).unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_budget_evicts_soonest_expiring() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for (key, ttl) in [("late", 120), ("soonest", 5), ("soon", 10)] {
+            Store::insert(&write_handle, &key.to_string(), 1, ttl).unwrap();
+        }
+        let budget = 3 * ESTIMATED_ENTRY_BYTES;
+        let policy = MemoryPolicy::EvictSoonestExpiring;
+        Store::reserve_memory(&write_handle, &reader, &"new".to_string(), budget, policy).unwrap();
+        assert!(Store::get(&reader, &"soonest".to_string()).unwrap().is_none());
+        assert!(Store::get(&reader, &"soon".to_string()).unwrap().is_some());
+        assert!(Store::get(&reader, &"late".to_string()).unwrap().is_some());
+        let result = Store::reserve_memory(&write_handle, &reader, &"new".to_string(), 0, policy);
+        assert!(matches!(result, Err(ModelError::StoreFull)));
+    }
+
+    #[tokio::test]
+    async fn memory_budget_evicts_least_recently_used() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        for key in ["oldest", "old", "recent"] {
+            Store::insert(&write_handle, &key.to_string(), 1, 60).unwrap();
+        }
+        // charging the oldest key makes it the most recently used
+        Store::inc_below_limit(&write_handle, &reader, "oldest".to_string(), 5, 60, WindowMode::Fixed).unwrap();
+        let budget = 3 * ESTIMATED_ENTRY_BYTES;
+        let policy = MemoryPolicy::EvictLeastRecentlyUsed;
+        Store::reserve_memory(&write_handle, &reader, &"new".to_string(), budget, policy).unwrap();
+        assert!(Store::get(&reader, &"old".to_string()).unwrap().is_none());
+        assert!(Store::get(&reader, &"oldest".to_string()).unwrap().is_some());
+        assert!(Store::get(&reader, &"recent".to_string()).unwrap().is_some());
+        assert_eq!(write_handle.lock().last_used.len(), 2);
+        let result = Store::reserve_memory(&write_handle, &reader, &"new".to_string(), 0, policy);
+        assert!(matches!(result, Err(ModelError::StoreFull)));
+    }
+
+    #[tokio::test]
+    async fn tag_persists_across_increments() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let reader = read_handle.handle();
+        let key = scoped_key("get_vault_items", "tagged");
+        Store::insert(&write_handle, &key, 1, 60).unwrap();
+        Store::set_tag(&write_handle, key.clone(), Some("vip".to_string())).unwrap();
+        Store::inc_below_limit(&write_handle, &reader, key.clone(), 10, 60, WindowMode::Fixed).unwrap();
+        Store::inc_below_limit(&write_handle, &reader, key.clone(), 10, 60, WindowMode::Fixed).unwrap();
+        let stored_value = Store::get(&reader, &key).unwrap().unwrap();
+        assert_eq!(stored_value.count, 3);
+        assert_eq!(stored_value.tag.as_deref(), Some("vip"));
+    }
+
+    #[tokio::test]
+    async fn delete_drops_scheduled_expiry() {
+        let (_, write_handle, _, _) = Store::init().await;
+        let key = scoped_key("put_vault_items", "deleted");
+        Store::insert(&write_handle, &key, 1, 60).unwrap();
+        Store::delete(&write_handle, &key).unwrap();
+        let writer = write_handle.lock();
+        assert!(!writer.contains_key(&key));
+        assert!(writer.ttl_queue.get(&key).is_none());
+        drop(writer);
+        assert!(matches!(Store::delete(&write_handle, &key), Err(ModelError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn tagging_missing_key_is_not_found() {
+        let (_, write_handle, _, _) = Store::init().await;
+        let result = Store::set_tag(&write_handle, "missing".to_string(), Some("flagged".to_string()));
+        assert!(matches!(result, Err(ModelError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn tags_are_bounded() {
+        let (read_handle, write_handle, _, _) = Store::init().await;
+        let key = scoped_key("get_vault_items", "long_tag");
+        Store::insert(&write_handle, &key, 1, 60).unwrap();
+        Store::set_tag(&write_handle, key.clone(), Some("v".repeat(MAX_TAG_BYTES))).unwrap();
+        let result = Store::set_tag(&write_handle, key.clone(), Some("v".repeat(MAX_TAG_BYTES + 1)));
+        assert!(matches!(result, Err(ModelError::TagTooLong(65))));
+        let stored_value = Store::get(&read_handle.handle(), &key).unwrap().unwrap();
+        assert_eq!(stored_value.tag.map(|tag| tag.len()), Some(MAX_TAG_BYTES));
     }
 }
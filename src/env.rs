@@ -1,7 +1,263 @@
+use crate::{GET_RATE_LIMIT, POST_RATE_LIMIT, PUT_RATE_LIMIT};
+use chrono::Duration;
+use rate_limiter_lib::{LimitType, MemoryPolicy, WindowMode, DEFAULT_EVICT_BATCH_SIZE, DEFAULT_SNAPSHOT_CHUNK_SIZE};
 use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf, time::Duration as StdDuration};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Env {
     pub server_port: usize,
     pub ttl: i64,
+    /// Bearer token required by the admin routes, admin routes are disabled when unset
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Shared secret other services present to `POST /check`, the route is disabled when unset
+    #[serde(default)]
+    pub check_secret: Option<String>,
+    /// Number of response bytes that cost one unit of quota on download routes
+    #[serde(default = "default_response_bytes_per_unit")]
+    pub response_bytes_per_unit: u64,
+    /// Comma separated scopes (e.g. put_vault_items) that are only charged for successful requests
+    #[serde(default)]
+    pub success_only_scopes: Vec<String>,
+    /// Comma separated bearer tokens (e.g. of health check bots) that are never rate limited
+    #[serde(default)]
+    pub allowlisted_tokens: Vec<String>,
+    /// Comma separated bearer tokens that are refused with a 403 on every route, even if allowlisted
+    #[serde(default)]
+    pub blocklisted_tokens: Vec<String>,
+    /// Number of proxies in front of the server trusted to append to X-Forwarded-For, the header is
+    /// ignored when this is 0
+    #[serde(default)]
+    pub trusted_proxy_count: usize,
+    /// Maximum number of requests served at once across the server, further requests get a 503
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Comma separated `content-type=cost` pairs charged for POST and PUT bodies, content types
+    /// that aren't listed cost 1
+    #[serde(default)]
+    pub content_type_costs: Vec<String>,
+    /// Approximate memory budget for the store, unbounded when unset
+    #[serde(default)]
+    pub max_memory_bytes: Option<usize>,
+    /// Most keys the store tracks, once reached the key closest to expiring makes way for a new one
+    #[serde(default)]
+    pub max_keys: Option<usize>,
+    /// What happens to new keys once max_memory_bytes is reached
+    #[serde(default)]
+    pub memory_policy: MemoryPolicy,
+    /// `fixed` windows reset once they expire, `sliding` windows count the last TTL seconds
+    #[serde(default)]
+    pub window_mode: WindowMode,
+    /// Limit for `POST /vault` per caller and window
+    #[serde(default = "default_post_limit")]
+    pub post_limit: LimitType,
+    /// Limit for `PUT /vault/:id` per caller and window
+    #[serde(default = "default_put_limit")]
+    pub put_limit: LimitType,
+    /// Limit for `GET /vault/items` per caller and window
+    #[serde(default = "default_get_limit")]
+    pub get_limit: LimitType,
+    /// Comma separated `scope=limit` pairs, these take precedence over the per route fields
+    #[serde(default)]
+    pub route_limits: Vec<String>,
+    /// Window length in seconds for `POST /vault`, `ttl` when unset
+    #[serde(default)]
+    pub post_ttl: Option<i64>,
+    /// Window length in seconds for `PUT /vault/:id`, `ttl` when unset
+    #[serde(default)]
+    pub put_ttl: Option<i64>,
+    /// Window length in seconds for `GET /vault/items`, `ttl` when unset
+    #[serde(default)]
+    pub get_ttl: Option<i64>,
+    /// Comma separated `scope=seconds` pairs, these take precedence over the per route fields
+    #[serde(default)]
+    pub route_ttls: Vec<String>,
+    /// File the store is saved to on shutdown and loaded from on startup, nothing is kept when unset
+    #[serde(default)]
+    pub snapshot_path: Option<PathBuf>,
+    /// Seconds between saves of the snapshot while serving, it is only saved on shutdown when unset
+    #[serde(default)]
+    pub snapshot_interval_secs: Option<u64>,
+    /// Most seconds each periodic save is pushed back by at random so replicas started together
+    /// don't all save at once, a tenth of the interval when unset
+    #[serde(default)]
+    pub snapshot_jitter_secs: Option<u64>,
+    /// Keys written per step of a periodic save before requests get to run
+    #[serde(default = "default_snapshot_chunk_size")]
+    pub snapshot_chunk_size: usize,
+    /// Most expired keys evicted in one pass of the TTL loop before requests get the lock back
+    #[serde(default = "default_evict_batch_size")]
+    pub evict_batch_size: usize,
+    /// `memory` keeps the limits in this process, `redis` keeps them at REDIS_URL shared between
+    /// replicas. The redis backend needs the server built with the `redis` feature.
+    #[serde(default)]
+    pub store_backend: StoreBackend,
+    /// Server the redis backend connects to, e.g. `redis://:password@localhost:6379/0`
+    #[serde(default)]
+    pub redis_url: Option<String>,
+    /// Milliseconds a key's state is kept in the local write-back cache in front of the redis
+    /// backend and charged locally, every check goes to redis when unset
+    #[serde(default)]
+    pub cache_ttl_ms: Option<i64>,
+    /// Milliseconds between pushes of locally cached charges to the store
+    #[serde(default = "default_cache_sync_ms")]
+    pub cache_sync_ms: u64,
+}
+
+/// Where the limits are kept
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreBackend {
+    /// In this process, each replica enforces its limits on its own
+    #[default]
+    Memory,
+    /// In Redis, shared between every replica pointed at the same server
+    Redis,
+}
+
+impl Env {
+    pub fn content_type_costs(&self) -> Result<HashMap<String, LimitType>, String> {
+        let costs = parse_pairs(&self.content_type_costs, "content-type=cost", 1)?;
+        Ok(costs
+            .into_iter()
+            .map(|(content_type, cost)| (content_type.to_ascii_lowercase(), cost))
+            .collect())
+    }
+
+    /// Limit of every route keyed by scope
+    pub fn route_limits(&self) -> Result<HashMap<String, LimitType>, String> {
+        let mut limits = HashMap::from([
+            ("add_vault_item".to_string(), self.post_limit),
+            ("put_vault_items".to_string(), self.put_limit),
+            ("get_vault_items".to_string(), self.get_limit),
+        ]);
+        limits.extend(parse_pairs(&self.route_limits, "scope=limit", 1)?);
+        match limits.iter().find(|(_, limit)| **limit < 1) {
+            Some((scope, _)) => Err(format!("limit for {} must be at least 1", scope)),
+            None => Ok(limits),
+        }
+    }
+
+    /// Window length of every route that doesn't use the global ttl keyed by scope
+    pub fn route_ttls(&self) -> Result<HashMap<String, i64>, String> {
+        let mut ttls: HashMap<String, i64> = [
+            ("add_vault_item", self.post_ttl),
+            ("put_vault_items", self.put_ttl),
+            ("get_vault_items", self.get_ttl),
+        ]
+        .into_iter()
+        .filter_map(|(scope, ttl)| Some((scope.to_string(), ttl?)))
+        .collect();
+        ttls.extend(parse_pairs(&self.route_ttls, "scope=seconds", 1)?);
+        match ttls.iter().find(|(_, ttl)| **ttl < 1) {
+            Some((scope, _)) => Err(format!("ttl for {} must be at least 1", scope)),
+            None => Ok(ttls),
+        }
+    }
+
+    /// The url of the Redis server the limits are kept in, None when they are kept in memory. The
+    /// settings that only apply to the in memory store are refused with the redis backend rather
+    /// than silently ignored.
+    pub fn redis_url(&self) -> Result<Option<&str>, String> {
+        if self.store_backend == StoreBackend::Memory {
+            return Ok(None);
+        }
+        let memory_only = [
+            ("SNAPSHOT_PATH", self.snapshot_path.is_some()),
+            ("MAX_KEYS", self.max_keys.is_some()),
+            ("MAX_MEMORY_BYTES", self.max_memory_bytes.is_some()),
+        ];
+        if let Some((name, _)) = memory_only.iter().find(|(_, set)| *set) {
+            return Err(format!("{} only applies to the memory store backend", name));
+        }
+        self.redis_url
+            .as_deref()
+            .map(Some)
+            .ok_or_else(|| "the redis store backend needs REDIS_URL".to_string())
+    }
+
+    /// How long keys are cached in front of the store and how often local charges are pushed to it,
+    /// None when the cache is off. Only the redis backend can be cached, a check against the memory
+    /// backend is already as cheap as one against the cache.
+    pub fn cache(&self) -> Result<Option<(Duration, StdDuration)>, String> {
+        match self.cache_ttl_ms {
+            None => Ok(None),
+            Some(_) if self.store_backend == StoreBackend::Memory => {
+                Err("CACHE_TTL_MS only applies to the redis store backend".to_string())
+            },
+            Some(cache_ttl_ms) if cache_ttl_ms < 1 => Err("cache ttl must be at least 1ms".to_string()),
+            Some(_) if self.cache_sync_ms < 1 => Err("cache sync interval must be at least 1ms".to_string()),
+            Some(cache_ttl_ms) => Ok(Some((
+                Duration::milliseconds(cache_ttl_ms),
+                StdDuration::from_millis(self.cache_sync_ms),
+            ))),
+        }
+    }
+
+    /// How often the snapshot is saved while serving and by how much each save is jittered, None
+    /// when it is only saved on shutdown
+    pub fn snapshot_schedule(&self) -> Result<Option<(StdDuration, StdDuration)>, String> {
+        match (self.snapshot_interval_secs, &self.snapshot_path) {
+            (None, _) => Ok(None),
+            (Some(_), None) => Err("snapshot interval is set without a snapshot path".to_string()),
+            (Some(0), _) => Err("snapshot interval must be at least 1s".to_string()),
+            _ if self.snapshot_chunk_size < 1 => Err("snapshot chunk size must be at least 1".to_string()),
+            (Some(interval_secs), Some(_)) => Ok(Some((
+                StdDuration::from_secs(interval_secs),
+                StdDuration::from_secs(self.snapshot_jitter_secs.unwrap_or(interval_secs / 10)),
+            ))),
+        }
+    }
+}
+
+/// Parse `name=value` pairs where every value is an integer of at least `min`
+fn parse_pairs(pairs: &[String], form: &str, min: LimitType) -> Result<HashMap<String, LimitType>, String> {
+    pairs
+        .iter()
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not in the form {}", pair, form))?;
+            let value = value
+                .trim()
+                .parse::<LimitType>()
+                .ok()
+                .filter(|value| *value >= min)
+                .ok_or_else(|| format!("value for {} must be an integer of at least {}", name, min))?;
+            Ok((name.trim().to_string(), value))
+        })
+        .collect()
+}
+
+fn default_response_bytes_per_unit() -> u64 {
+    1024
+}
+
+fn default_max_in_flight() -> usize {
+    1024
+}
+
+fn default_cache_sync_ms() -> u64 {
+    100
+}
+
+fn default_snapshot_chunk_size() -> usize {
+    DEFAULT_SNAPSHOT_CHUNK_SIZE
+}
+
+fn default_evict_batch_size() -> usize {
+    DEFAULT_EVICT_BATCH_SIZE
+}
+
+fn default_post_limit() -> LimitType {
+    POST_RATE_LIMIT
+}
+
+fn default_put_limit() -> LimitType {
+    PUT_RATE_LIMIT
+}
+
+fn default_get_limit() -> LimitType {
+    GET_RATE_LIMIT
 }
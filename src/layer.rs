@@ -0,0 +1,153 @@
+use crate::{
+    error_response,
+    peer_addr,
+    quota_response,
+    with_rate_limit_headers,
+    AppState,
+    Charged,
+    RequestError,
+};
+use axum::{
+    http::Request,
+    response::{IntoResponse, Response},
+};
+use rate_limiter_lib::{scoped_key, LimitType, ModelError};
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+/// Rate limit a route by caller before its handler runs. Callers are tracked under `scope:caller`
+/// the same way the handlers key them (see AppState::caller_id), so the layer and a handler
+/// charging the same scope share one budget. The global limit multiplier, memory budget and window mode still apply,
+/// allowlisted tokens pass straight through and blocklisted ones are refused.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    app_state: Arc<AppState>,
+    scope: &'static str,
+    limit: LimitType,
+    ttl: i64,
+}
+
+impl RateLimitLayer {
+    pub fn new(app_state: Arc<AppState>, scope: &'static str, limit: LimitType, ttl: i64) -> Self {
+        RateLimitLayer {
+            app_state,
+            scope,
+            limit,
+            ttl,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S, B> Service<Request<B>> for RateLimit<S>
+where
+    S: Service<Request<B>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+    type Response = Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // the clone is not necessarily ready, keep the one poll_ready was called on
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        // without a token or an address there is nobody to charge, refused the same way the
+        // handlers refuse it
+        let Some(caller) = self.layer.app_state.caller_id(request.headers(), peer_addr(&request)) else {
+            return Box::pin(std::future::ready(Ok(RequestError::CallerRequired.into_response())));
+        };
+        if self.layer.app_state.is_blocklisted(&caller) {
+            return Box::pin(std::future::ready(Ok(error_response(ModelError::Blocked))));
+        }
+        if self.layer.app_state.is_allowlisted(&caller) {
+            return Box::pin(inner.call(request));
+        }
+        let RateLimitLayer {
+            app_state,
+            scope,
+            limit,
+            ttl,
+        } = self.layer.clone();
+        Box::pin(async move {
+            let limit_key = scoped_key(scope, &caller);
+            let limit = app_state.effective_limit(limit);
+            let charged = match app_state.reserve_memory(&limit_key).await {
+                Ok(()) => {
+                    app_state
+                        .store
+                        .inc_below_limit(limit_key.clone(), limit, ttl, app_state.window_mode)
+                        .await
+                },
+                Err(e) => Err(e),
+            };
+            match charged {
+                Ok(quota) => {
+                    let mut response = inner.call(request).await?;
+                    response.extensions_mut().insert(Charged { key: limit_key, cost: 1 });
+                    Ok(with_rate_limit_headers(response, limit, quota.remaining, quota.reset_at))
+                },
+                Err(e) => Ok(quota_response(limit, Err(e), "", app_state.store.now())),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{bearer_request, test_state};
+    use axum::{http::StatusCode, routing::get, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn throttled_requests_never_reach_the_handler() {
+        let app_state = test_state().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        let app = Router::new().route(
+            "/wrapped",
+            get(move || async move {
+                handler_calls.fetch_add(1, Ordering::SeqCst);
+                "handled"
+            })
+            .route_layer(RateLimitLayer::new(app_state.clone(), "wrapped", 2, 60)),
+        );
+        for _ in 0..2 {
+            let response = app.clone().oneshot(bearer_request("GET", "/wrapped", "layered")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app.oneshot(bearer_request("GET", "/wrapped", "layered")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(axum::http::header::RETRY_AFTER));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
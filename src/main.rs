@@ -1,101 +1,2130 @@
 mod env;
+mod layer;
+mod metrics;
 use axum::{
-    extract::{Path, State},
-    headers::{authorization::Bearer, Authorization},
-    http::StatusCode,
+    body::HttpBody,
+    extract::{ConnectInfo, Path, Query, State},
+    headers::{authorization::Bearer, Authorization, HeaderMapExt},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER},
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+        Method,
+        Request,
+        StatusCode,
+    },
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post, put},
+    Extension,
+    Json,
+    routing::{delete, get, post, put, MethodRouter},
     Router,
     TypedHeader,
 };
+use chrono::{DateTime, Duration, Utc};
 use env::Env;
-use evmap::{ReadHandleFactory, WriteHandle};
-use parking_lot::Mutex;
-use rate_limiter_lib::{InternalValue, KeyType, LimitType, Store};
-use std::{error::Error, net::SocketAddr, sync::Arc};
+use evmap::ReadHandleFactory;
+use layer::RateLimitLayer;
+use metrics::{MeteredStore, Metrics};
+use rate_limiter_lib::{
+    CachedStore,
+    InternalValue,
+    KeyType,
+    LimitType,
+    MemoryPolicy,
+    MemoryStore,
+    ModelError,
+    Quota,
+    RateLimiter,
+    Store,
+    SystemClock,
+    WindowMode,
+    client_ip,
+    scoped_key,
+};
+#[cfg(feature = "redis")]
+use rate_limiter_lib::RedisStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    error::Error,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration as StdDuration,
+};
+use parking_lot::RwLock;
+use tokio::{signal, sync::Semaphore, task::JoinHandle, time};
 
 pub struct AppState {
-    pub store_reader: ReadHandleFactory<KeyType, InternalValue>,
-    pub store_writer: Arc<Mutex<WriteHandle<KeyType, InternalValue>>>,
+    pub store: Arc<dyn RateLimiter + Send + Sync>,
     pub ttl: i64,
+    pub admin_token: Option<String>,
+    pub check_secret: Option<String>,
+    pub response_bytes_per_unit: u64,
+    pub success_only_scopes: HashSet<String>,
+    /// Bearer tokens that skip rate limiting entirely
+    pub allowlisted_tokens: HashSet<String>,
+    /// Bearer tokens refused outright, editable at runtime through the admin routes
+    pub blocklisted_tokens: RwLock<HashSet<String>>,
+    /// Number of proxies trusted to append to X-Forwarded-For, see client_ip
+    pub trusted_proxy_count: usize,
+    pub in_flight: Semaphore,
+    /// f64 bits of the multiplier applied to every route limit
+    pub limit_multiplier: AtomicU64,
+    pub content_type_costs: HashMap<String, LimitType>,
+    pub max_memory_bytes: Option<usize>,
+    pub memory_policy: MemoryPolicy,
+    pub window_mode: WindowMode,
+    /// Configured limit of every route keyed by scope, before the multiplier is applied
+    pub route_limits: HashMap<String, LimitType>,
+    /// Window length in seconds of the routes that don't use `ttl` keyed by scope
+    pub route_ttls: HashMap<String, i64>,
+    /// Tallies of every charge made through `store`
+    pub metrics: Arc<Metrics>,
+}
+
+impl AppState {
+    pub fn is_admin(&self, token: &str) -> bool {
+        self.admin_token.as_deref() == Some(token)
+    }
+
+    /// Allowlisted callers are neither charged nor throttled on any route
+    pub fn is_allowlisted(&self, token: &str) -> bool {
+        self.allowlisted_tokens.contains(token)
+    }
+
+    /// Blocklisted callers are refused on every route before any quota is looked at. This takes
+    /// precedence over the allowlist so a token that ends up on both is still refused.
+    pub fn is_blocklisted(&self, token: &str) -> bool {
+        self.blocklisted_tokens.read().contains(token)
+    }
+
+    /// Whether requests from the caller are counted against its limits at all
+    pub fn is_limited(&self, caller: &str) -> bool {
+        !self.is_allowlisted(caller) && !self.is_blocklisted(caller)
+    }
+
+    /// Who a request is charged to, its bearer token when it has one and otherwise the address of
+    /// the client so anonymous traffic is limited per source. Addresses are prefixed so they are
+    /// tracked apart from tokens, and a token carrying the prefix is refused rather than let into an
+    /// address's bucket. None when there is no usable caller, e.g. a connection without a peer.
+    pub fn caller_id(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<String> {
+        if let Some(bearer) = headers.typed_get::<Authorization<Bearer>>() {
+            return Some(bearer.token())
+                .filter(|token| !token.starts_with(CLIENT_IP_PREFIX))
+                .map(str::to_string);
+        }
+        let forwarded_for = headers.get(&X_FORWARDED_FOR).and_then(|value| value.to_str().ok());
+        let ip = client_ip(forwarded_for, peer?.ip(), self.trusted_proxy_count);
+        Some(format!("{}{}", CLIENT_IP_PREFIX, ip))
+    }
+
+    pub fn limit_multiplier(&self) -> f64 {
+        f64::from_bits(self.limit_multiplier.load(Ordering::Relaxed))
+    }
+
+    /// Replace the global limit multiplier, clamped to LIMIT_MULTIPLIER_RANGE. Returns the value
+    /// actually applied.
+    pub fn set_limit_multiplier(&self, multiplier: f64) -> f64 {
+        let multiplier = multiplier.clamp(*LIMIT_MULTIPLIER_RANGE.start(), *LIMIT_MULTIPLIER_RANGE.end());
+        self.limit_multiplier.store(multiplier.to_bits(), Ordering::Relaxed);
+        multiplier
+    }
+
+    /// What a request body costs against the limit based on its content type, parameters such as
+    /// the multipart boundary are ignored and unknown or missing content types cost 1.
+    pub fn content_type_cost(&self, headers: &HeaderMap) -> LimitType {
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| content_type.split(';').next())
+            .and_then(|essence| self.content_type_costs.get(&essence.trim().to_ascii_lowercase()))
+            .copied()
+            .unwrap_or(1)
+    }
+
+    /// Apply the configured memory budget before a request may start tracking a new key
+    pub async fn reserve_memory(&self, key: &KeyType) -> Result<(), ModelError> {
+        match self.max_memory_bytes {
+            Some(max_memory_bytes) => self.store.reserve_memory(key, max_memory_bytes, self.memory_policy).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The configured limit of a route. A scope without one gets 0, which the store refuses as an
+    /// invalid limit, so a route wired up without a limit fails loudly rather than going unlimited.
+    pub fn route_limit(&self, scope: &str) -> LimitType {
+        self.route_limits.get(scope).copied().unwrap_or_default()
+    }
+
+    /// Whether reads and writes of the resource are limited apart and together, which takes a
+    /// limit for each of the `{scope}_reads`, `{scope}_writes` and `{scope}` scopes
+    pub fn limits_reads_and_writes(&self, scope: &str) -> bool {
+        [reads_scope(scope), writes_scope(scope), scope.to_string()]
+            .iter()
+            .all(|scope| self.route_limits.contains_key(scope))
+    }
+
+    /// The window length of a route, the global ttl unless one was configured for the scope
+    pub fn route_ttl(&self, scope: &str) -> i64 {
+        self.route_ttls.get(scope).copied().unwrap_or(self.ttl)
+    }
+
+    /// The limit a route is enforced at once the global multiplier has been applied. Scaling down
+    /// never takes a configured limit below one request.
+    pub fn effective_limit(&self, limit: LimitType) -> LimitType {
+        let scaled = (limit as f64 * self.limit_multiplier()).floor() as LimitType;
+        if limit > 0 {
+            scaled.max(1)
+        } else {
+            scaled
+        }
+    }
 }
 
 pub fn routes(app_state: Arc<AppState>) -> Router {
-    Router::new()
-        .route("/vault", post(add_vault_item))
-        .route("/vault/items", get(get_vault_items))
-        .route("/vault/:id", put(put_vault_items))
+    let mut vault = Router::new()
+        .route("/vault", charge_on_success(post(add_vault_item), &app_state, "add_vault_item"))
+        .route(
+            "/vault/items",
+            charge_on_success(
+                get(get_vault_items)
+                    .route_layer(RateLimitLayer::new(
+                        app_state.clone(),
+                        "get_vault_items",
+                        app_state.route_limit("get_vault_items"),
+                        app_state.route_ttl("get_vault_items"),
+                    ))
+                    .route_layer(middleware::from_fn_with_state(
+                        (app_state.clone(), "get_vault_items"),
+                        charge_response_bytes,
+                    )),
+                &app_state,
+                "get_vault_items",
+            ),
+        )
+        .route("/vault/:id", charge_on_success(put(put_vault_items), &app_state, "put_vault_items"));
+    if app_state.limits_reads_and_writes(VAULT_SCOPE) {
+        vault = vault.route_layer(middleware::from_fn_with_state(
+            (app_state.clone(), VAULT_SCOPE),
+            limit_reads_and_writes,
+        ));
+    }
+    vault
+        .route("/vault/limits", get(get_limits).delete(delete_limits))
+        .route("/vault/limits/multiplier", put(put_limit_multiplier))
+        .route("/vault/limits/debug", get(get_limits_debug))
+        .route("/vault/limits/:key", delete(delete_limit))
+        .route("/vault/limits/:key/tag", put(put_limit_tag))
+        .route("/vault/blocklist/:token", put(put_blocklisted_token).delete(delete_blocklisted_token))
+        .route("/check", post(check_limit))
+        .route("/metrics", get(get_metrics))
+        .layer(middleware::from_fn_with_state(app_state.clone(), limit_in_flight))
         .with_state(app_state)
 }
+/// Default route limits, see Env
 const POST_RATE_LIMIT: LimitType = 3;
 const PUT_RATE_LIMIT: LimitType = 60;
 const GET_RATE_LIMIT: LimitType = 1200;
+const DEFAULT_LIMIT_MULTIPLIER: f64 = 1.0;
+/// Scope of the limits shared by every vault route, see limit_reads_and_writes
+const VAULT_SCOPE: &str = "vault";
+/// Marks a caller id resolved from the client address rather than a token
+const CLIENT_IP_PREFIX: &str = "ip:";
+const LIMIT_MULTIPLIER_RANGE: RangeInclusive<f64> = 0.1..=10.0;
+/// Window lengths a sidecar caller may ask for, up to a day
+const CHECK_TTL_RANGE: RangeInclusive<i64> = 1..=86_400;
+
+/// Limiting algorithms a sidecar caller may ask for
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckAlgorithm {
+    #[default]
+    FixedWindow,
+    SlidingWindow,
+}
+
+impl CheckAlgorithm {
+    fn window_mode(self) -> WindowMode {
+        match self {
+            CheckAlgorithm::FixedWindow => WindowMode::Fixed,
+            CheckAlgorithm::SlidingWindow => WindowMode::Sliding,
+        }
+    }
+}
+
+/// Body of `POST /check`, charges one request against `key` in its own namespace so sidecar keys
+/// never collide with the vault routes.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CheckRequest {
+    pub key: String,
+    pub limit: LimitType,
+    pub ttl: i64,
+    #[serde(default)]
+    pub algorithm: CheckAlgorithm,
+}
+
+/// Decision returned by `POST /check`, `retry_after` is only set when the request was throttled by
+/// a key that expires
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CheckResponse {
+    pub allowed: bool,
+    pub key: String,
+    pub limit: LimitType,
+    pub algorithm: CheckAlgorithm,
+    pub retry_after: Option<i64>,
+}
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn Error>> {
     let _ = dotenv::dotenv().ok();
     let env = envy::from_env::<Env>()?;
     env_logger::init();
-    let (read_handle, write_handle, timer_handler) = Store::init().await;
+    log::info!("trusting {} proxy hops in X-Forwarded-For", env.trusted_proxy_count);
+    let content_type_costs = env.content_type_costs()?;
+    let route_limits = env.route_limits()?;
+    let route_ttls = env.route_ttls()?;
+    let (read_handle, write_handle, timer_handler, stop_timer) =
+        Store::init_with_batch_size(Arc::new(SystemClock), env.evict_batch_size).await;
+    write_handle.set_max_keys(env.max_keys);
+    if let Some(snapshot_path) = &env.snapshot_path {
+        let loaded = Store::load_snapshot(&write_handle, snapshot_path, env.max_memory_bytes)?;
+        log::info!("loaded {} keys from {}", loaded, snapshot_path.display());
+    }
+    let backend: Arc<dyn RateLimiter + Send + Sync> = match env.redis_url()? {
+        None => Arc::new(MemoryStore::new(read_handle.clone(), write_handle.clone())),
+        Some(redis_url) => {
+            log::info!("keeping the limits in redis");
+            connect_redis(redis_url).await?
+        },
+    };
+    // only ever in front of redis, env.cache refuses a cache in front of the memory store
+    let (backend, cache): (Arc<dyn RateLimiter + Send + Sync>, _) = match env.cache()? {
+        None => (backend, None),
+        Some((cache_ttl, sync_every)) => {
+            let cache = CachedStore::new(backend, Arc::new(SystemClock), cache_ttl);
+            spawn_cache_sync(cache.clone(), sync_every);
+            (Arc::new(cache.clone()), Some(cache))
+        },
+    };
+    let periodic_snapshots = match (env.snapshot_schedule()?, &env.snapshot_path) {
+        (Some((every, jitter)), Some(snapshot_path)) => Some(spawn_snapshots(
+            read_handle.clone(),
+            snapshot_path.clone(),
+            every,
+            jitter,
+            env.snapshot_chunk_size,
+        )),
+        _ => None,
+    };
+    let metrics = Arc::new(Metrics::default());
     let app_state = Arc::new(AppState {
-        store_reader: read_handle,
-        store_writer: write_handle,
+        store: Arc::new(MeteredStore::new(backend, metrics.clone())),
         ttl: env.ttl,
+        admin_token: env.admin_token,
+        check_secret: env.check_secret,
+        response_bytes_per_unit: env.response_bytes_per_unit,
+        success_only_scopes: env.success_only_scopes.into_iter().collect(),
+        allowlisted_tokens: env.allowlisted_tokens.into_iter().collect(),
+        blocklisted_tokens: RwLock::new(env.blocklisted_tokens.into_iter().collect()),
+        trusted_proxy_count: env.trusted_proxy_count,
+        in_flight: Semaphore::new(env.max_in_flight),
+        limit_multiplier: AtomicU64::new(DEFAULT_LIMIT_MULTIPLIER.to_bits()),
+        content_type_costs,
+        max_memory_bytes: env.max_memory_bytes,
+        memory_policy: env.memory_policy,
+        window_mode: env.window_mode,
+        route_limits,
+        route_ttls,
+        metrics,
     });
 
-    let app = routes(app_state);
+    let app = routes(app_state.clone());
     let addr = SocketAddr::from(([127, 0, 0, 1], env.server_port as u16));
     log::info!("listening on {}", addr);
-    let server_future = axum::Server::bind(&addr).serve(app.into_make_service());
-    let _ = tokio::join!(server_future, timer_handler);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    // in flight requests have been served, publish their writes and stop expiring keys
+    if let Some(cache) = &cache {
+        cache.sync().await?;
+    }
+    let _ = stop_timer.send(());
+    timer_handler.await?;
+    // a periodic save still in progress would race the final one for the partial file
+    if let Some(periodic_snapshots) = periodic_snapshots {
+        periodic_snapshots.abort();
+    }
+    if let Some(snapshot_path) = &env.snapshot_path {
+        let saved = Store::save_snapshot(&read_handle.handle(), snapshot_path)?;
+        log::info!("saved {} keys to {}", saved, snapshot_path.display());
+    }
+    log::info!("shut down");
     Ok(())
 }
 
-async fn get_vault_items(
-    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+#[cfg(feature = "redis")]
+async fn connect_redis(redis_url: &str) -> Result<Arc<dyn RateLimiter + Send + Sync>, Box<dyn Error>> {
+    Ok(Arc::new(RedisStore::connect(redis_url, Arc::new(SystemClock)).await?))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn connect_redis(_redis_url: &str) -> Result<Arc<dyn RateLimiter + Send + Sync>, Box<dyn Error>> {
+    Err("the redis store backend needs the server built with the redis feature".into())
+}
+
+/// Push the charges made against the local cache to the store every `sync_every`
+fn spawn_cache_sync(cache: CachedStore, sync_every: StdDuration) {
+    tokio::spawn(async move {
+        let mut ticks = time::interval(sync_every);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = cache.sync().await {
+                log::error!("unable to push cached charges to the store: {}", e);
+            }
+        }
+    });
+}
+
+/// Save the store to `snapshot_path` every `every` plus up to `jitter` at random, in chunks of
+/// `chunk_size` keys so requests keep being served while a large store is written out
+fn spawn_snapshots(
+    reader_factory: ReadHandleFactory<KeyType, InternalValue>,
+    snapshot_path: PathBuf,
+    every: StdDuration,
+    jitter: StdDuration,
+    chunk_size: usize,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            time::sleep(every + random_up_to(jitter)).await;
+            match Store::save_snapshot_in_chunks(&reader_factory, &snapshot_path, chunk_size).await {
+                Ok(saved) => log::debug!("saved {} keys to {}", saved, snapshot_path.display()),
+                Err(e) => log::error!("unable to save the snapshot to {}: {}", snapshot_path.display(), e),
+            }
+        }
+    })
+}
+
+/// A duration between zero and `max`, drawn from the randomly seeded std hasher
+fn random_up_to(max: StdDuration) -> StdDuration {
+    let random = RandomState::new().build_hasher().finish();
+    StdDuration::from_millis(random % (max.as_millis() as u64 + 1))
+}
+
+/// Resolves on Ctrl-C or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            log::error!("unable to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            },
+            Err(e) => {
+                log::error!("unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            },
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    log::info!("shutdown requested, draining in flight requests");
+}
+
+/// JSON body of every error response. `error` is one of ModelError::code or RequestError::code, the
+/// remaining fields are only set when the caller was throttled.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub error: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<LimitType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<LimitType>,
+}
+
+/// Requests refused before the store is involved, answered with the same ErrorBody as store errors
+/// so clients have a single error format to handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// Neither a bearer token nor a client address to charge the request to
+    CallerRequired,
+    AdminRequired,
+    CheckSecretRequired,
+    /// MAX_IN_FLIGHT requests are already being served
+    AtCapacity,
+    EmptyPrefix,
+    InvalidMultiplier,
+}
+
+impl RequestError {
+    /// Stable machine readable name of the error, see ModelError::code
+    pub fn code(self) -> &'static str {
+        match self {
+            RequestError::CallerRequired => "caller_required",
+            RequestError::AdminRequired => "admin_required",
+            RequestError::CheckSecretRequired => "check_secret_required",
+            RequestError::AtCapacity => "at_capacity",
+            RequestError::EmptyPrefix => "empty_prefix",
+            RequestError::InvalidMultiplier => "invalid_multiplier",
+        }
+    }
+
+    fn status(self) -> StatusCode {
+        match self {
+            RequestError::CallerRequired | RequestError::AdminRequired | RequestError::CheckSecretRequired => {
+                StatusCode::UNAUTHORIZED
+            },
+            RequestError::AtCapacity => StatusCode::SERVICE_UNAVAILABLE,
+            RequestError::EmptyPrefix | RequestError::InvalidMultiplier => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            RequestError::CallerRequired => "Bearer token or client address required",
+            RequestError::AdminRequired => "Admin token required",
+            RequestError::CheckSecretRequired => "Check secret required",
+            RequestError::AtCapacity => "Server is at capacity please retry later",
+            RequestError::EmptyPrefix => "Prefix must not be empty",
+            RequestError::InvalidMultiplier => "Multiplier must be a number",
+        }
+    }
+}
+
+impl IntoResponse for RequestError {
+    fn into_response(self) -> Response {
+        let body = Json(ErrorBody {
+            error: self.code().to_string(),
+            message: self.message().to_string(),
+            retry_after_secs: None,
+            limit: None,
+            remaining: None,
+        });
+        (self.status(), body).into_response()
+    }
+}
+
+/// Build the response returned whenever a store operation fails. Every rate limited path goes
+/// through here so the status, headers and body can't drift between routes.
+pub fn error_response(e: ModelError) -> Response {
+    let status = match e {
+        ModelError::NotFound => StatusCode::NOT_FOUND,
+        ModelError::AlreadyPresent => StatusCode::CONFLICT,
+        ModelError::StoreFull | ModelError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        ModelError::Blocked => StatusCode::FORBIDDEN,
+        ModelError::TagTooLong(_) => StatusCode::BAD_REQUEST,
+        // a route charging a bad cost, limit or rate is our bug, not the caller's
+        ModelError::InvalidCost(_) |
+        ModelError::InvalidLimit(_) |
+        ModelError::InvalidTtl(_) |
+        ModelError::InvalidRate(_) |
+        ModelError::InvalidCount(_) |
+        ModelError::Overflow => StatusCode::INTERNAL_SERVER_ERROR,
+        ModelError::PastRateLimit { .. } | ModelError::LimitExhausted { .. } | ModelError::WouldBlock => {
+            StatusCode::TOO_MANY_REQUESTS
+        },
+    };
+    let (retry_after_secs, limit) = match e {
+        ModelError::PastRateLimit { retry_after_secs, limit } => (Some(retry_after_secs.max(0)), Some(limit)),
+        // there is no time to retry at, so no Retry-After either
+        ModelError::LimitExhausted { limit } => (None, Some(limit)),
+        _ => (None, None),
+    };
+    let body = Json(ErrorBody {
+        error: e.code().to_string(),
+        message: e.to_string(),
+        retry_after_secs,
+        limit,
+        // a throttled caller has nothing left until the window resets
+        remaining: limit.map(|_| 0),
+    });
+    match retry_after_secs {
+        Some(retry_after_secs) => (status, [(RETRY_AFTER, retry_after_secs.to_string())], body).into_response(),
+        None => (status, body).into_response(),
+    }
+}
+
+/// The error response for a store error caused by what the caller sent rather than by a route
+pub fn bad_request(e: ModelError) -> Response {
+    let mut response = error_response(e);
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    response
+}
+
+pub static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub static X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+pub static X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+pub static X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+/// Tell the caller where they stand on a route so clients can pace themselves. The reset is the
+/// unix timestamp at which the full limit is available again.
+pub fn with_rate_limit_headers(
+    mut response: Response,
+    limit: LimitType,
+    remaining: LimitType,
+    reset_at: DateTime<Utc>,
+) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(X_RATELIMIT_LIMIT.clone(), HeaderValue::from(limit));
+    headers.insert(X_RATELIMIT_REMAINING.clone(), HeaderValue::from(remaining));
+    headers.insert(X_RATELIMIT_RESET.clone(), HeaderValue::from(reset_at.timestamp()));
+    response
+}
+
+/// Attached to the response of a request that was charged against a limit, with what it cost, so
+/// release_on_failure can hand exactly that back if the request fails after the charge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Charged {
+    pub key: KeyType,
+    pub cost: LimitType,
+}
+
+/// Respond to a charge against a route limit. Allowed and throttled requests both carry the
+/// X-RateLimit headers, errors that aren't about the caller's quota (e.g. a full store) don't.
+/// `now` is the store's time, which a throttled caller's reset is counted from.
+pub fn quota_response(
+    limit: LimitType,
+    charged: Result<(Quota, Charged), ModelError>,
+    body: &'static str,
+    now: DateTime<Utc>,
+) -> Response {
+    match charged {
+        Ok((quota, charge)) => with_rate_limit_headers(
+            (StatusCode::OK, Extension(charge), body).into_response(),
+            limit,
+            quota.remaining,
+            quota.reset_at,
+        ),
+        Err(e @ ModelError::PastRateLimit { retry_after_secs, .. }) => with_rate_limit_headers(
+            error_response(e),
+            limit,
+            0,
+            now + Duration::seconds(retry_after_secs.max(0)),
+        ),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Bound the number of requests in flight across the whole server regardless of any per key
+/// budget. This is the last line of defense under overload so it sheds load instead of queueing.
+pub async fn limit_in_flight<B>(
     State(app_state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
 ) -> Response {
-    if let Err(e) = Store::inc_below_limit(
-        &app_state.store_writer,
-        &app_state.store_reader.handle(),
-        format!("get_vault_items_{}",key.token()),
-        GET_RATE_LIMIT,
-        app_state.ttl,
-    ) {
-        return (StatusCode::TOO_MANY_REQUESTS, e.to_string()).into_response();
+    let Ok(_permit) = app_state.in_flight.try_acquire() else {
+        return RequestError::AtCapacity.into_response();
+    };
+    next.run(request).await
+}
+
+/// Only charge the scope for requests that succeed when it has been configured that way. The
+/// handler still reserves quota up front so concurrent requests can't overshoot the limit, the
+/// reservation is then released if the handler fails.
+fn charge_on_success(
+    method_router: MethodRouter<Arc<AppState>>,
+    app_state: &Arc<AppState>,
+    scope: &'static str,
+) -> MethodRouter<Arc<AppState>> {
+    if app_state.success_only_scopes.contains(scope) {
+        method_router.route_layer(middleware::from_fn_with_state(app_state.clone(), release_on_failure))
+    } else {
+        method_router
+    }
+}
+
+/// Refund what the handler charged when it did not produce a successful response. Only responses
+/// marked as Charged are refunded, so a request that was throttled, allowlisted or failed before it
+/// was charged is passed through untouched.
+pub async fn release_on_failure<B>(
+    State(app_state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let response = next.run(request).await;
+    if response.status().is_success() {
+        return response;
+    }
+    if let Some(Charged { key, cost }) = response.extensions().get::<Charged>().cloned() {
+        if let Err(e) = app_state.store.refund(key, cost).await {
+            log::error!("failed to release quota for failed request: {}", e);
+        }
     }
+    response
+}
+
+/// Charge the caller for the bytes served once the handler has produced its response so a client
+/// that downloads a lot is throttled on its subsequent requests to the same scope. Only successful
+/// responses are charged, an error body isn't something the caller asked to download.
+pub async fn charge_response_bytes<B>(
+    State((app_state, scope)): State<(Arc<AppState>, &'static str)>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let caller = app_state.caller_id(request.headers(), peer_addr(&request));
+    let response = next.run(request).await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let Some(caller) = caller.filter(|caller| app_state.is_limited(caller)) else {
+        return response;
+    };
+    let bytes_served = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact())
+        .unwrap_or_default();
+    let units = bytes_served / app_state.response_bytes_per_unit.max(1);
+    if units > 0 {
+        let limit_key = scoped_key(scope, &caller);
+        let charged = match app_state.reserve_memory(&limit_key).await {
+            Ok(()) => {
+                app_state
+                    .store
+                    .inc_by(
+                        limit_key,
+                        LimitType::try_from(units).unwrap_or(LimitType::MAX),
+                        app_state.route_ttl(scope),
+                        app_state.window_mode,
+                    )
+                    .await
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = charged {
+            log::error!("failed to charge {} bytes served: {}", bytes_served, e);
+        }
+    }
+    response
+}
+
+fn reads_scope(scope: &str) -> String {
+    format!("{}_reads", scope)
+}
+
+fn writes_scope(scope: &str) -> String {
+    format!("{}_writes", scope)
+}
+
+/// Limit a resource's reads (GET, HEAD and OPTIONS) and writes (every other method) separately and
+/// together. A request is charged against its `{scope}_reads` or `{scope}_writes` limit and the
+/// combined `{scope}` limit at once, so either both are charged or, when either is reached, neither.
+/// This runs ahead of the routes' own limits and the headers report whichever limit the caller is
+/// closest to.
+pub async fn limit_reads_and_writes<B>(
+    State((app_state, scope)): State<(Arc<AppState>, &'static str)>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(caller) = app_state.caller_id(request.headers(), peer_addr(&request)) else {
+        return RequestError::CallerRequired.into_response();
+    };
+    if app_state.is_blocklisted(&caller) {
+        return error_response(ModelError::Blocked);
+    }
+    if app_state.is_allowlisted(&caller) {
+        return next.run(request).await;
+    }
+    let method_scope = match *request.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => reads_scope(scope),
+        _ => writes_scope(scope),
+    };
+    let items: Vec<(KeyType, LimitType, i64)> = [method_scope.as_str(), scope]
+        .into_iter()
+        .map(|scope| {
+            let limit = app_state.effective_limit(app_state.route_limit(scope));
+            (scoped_key(scope, &caller), limit, app_state.route_ttl(scope))
+        })
+        .collect();
+    for (key, ..) in &items {
+        if let Err(e) = app_state.reserve_memory(key).await {
+            return error_response(e);
+        }
+    }
+    let (quota, limit) = match app_state.store.inc_all_below_limit(&items, app_state.window_mode).await {
+        Ok(quotas) => match quotas.into_iter().zip(&items).min_by_key(|(quota, _)| quota.remaining) {
+            Some((quota, (_, limit, _))) => (quota, *limit),
+            None => return next.run(request).await,
+        },
+        Err(e @ ModelError::PastRateLimit { limit, .. }) => {
+            return quota_response(limit, Err(e), "", app_state.store.now())
+        },
+        Err(e) => return error_response(e),
+    };
+    let response = next.run(request).await;
+    let route_remaining = response
+        .headers()
+        .get(&X_RATELIMIT_REMAINING)
+        .and_then(|remaining| remaining.to_str().ok()?.parse::<LimitType>().ok());
+    if route_remaining.is_some_and(|route_remaining| route_remaining <= quota.remaining) {
+        response
+    } else {
+        with_rate_limit_headers(response, limit, quota.remaining, quota.reset_at)
+    }
+}
+
+/// The peer address of the connection, only known when the server was started with connect info
+pub fn peer_addr<B>(request: &Request<B>) -> Option<SocketAddr> {
+    request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(peer)| *peer)
+}
+
+/// Rate limited by the RateLimitLayer wrapping the route
+async fn get_vault_items() -> Response {
     (StatusCode::OK, "Returned vault items").into_response()
 }
 
 pub async fn add_vault_item(
-    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> Response {
-    if let Err(e) = Store::inc_below_limit(
-        &app_state.store_writer,
-        &app_state.store_reader.handle(),
-        format!("add_vault_item_{}",key.token()),
-        POST_RATE_LIMIT,
-        app_state.ttl,
-    ) {
-        return (StatusCode::TOO_MANY_REQUESTS, e.to_string()).into_response();
+    let Some(caller) = app_state.caller_id(&headers, connect_info.map(|ConnectInfo(peer)| peer)) else {
+        return RequestError::CallerRequired.into_response();
+    };
+    if app_state.is_blocklisted(&caller) {
+        return error_response(ModelError::Blocked);
     }
-    (StatusCode::OK, "Vault key added").into_response()
+    if app_state.is_allowlisted(&caller) {
+        return (StatusCode::OK, "Vault key added").into_response();
+    }
+    let limit_key = scoped_key("add_vault_item", &caller);
+    let limit = app_state.effective_limit(app_state.route_limit("add_vault_item"));
+    let cost = app_state.content_type_cost(&headers);
+    let charged = match app_state.reserve_memory(&limit_key).await {
+        Ok(()) => {
+            app_state
+                .store
+                .inc_by_below_limit(
+                    limit_key.clone(),
+                    limit,
+                    app_state.route_ttl("add_vault_item"),
+                    cost,
+                    app_state.window_mode,
+                )
+                .await
+        },
+        Err(e) => Err(e),
+    };
+    let charged = charged.map(|quota| (quota, Charged { key: limit_key, cost }));
+    quota_response(limit, charged, "Vault key added", app_state.store.now())
 }
 
 pub async fn put_vault_items(
-    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     Path(_id): Path<String>,
     State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(caller) = app_state.caller_id(&headers, connect_info.map(|ConnectInfo(peer)| peer)) else {
+        return RequestError::CallerRequired.into_response();
+    };
+    if app_state.is_blocklisted(&caller) {
+        return error_response(ModelError::Blocked);
+    }
+    if app_state.is_allowlisted(&caller) {
+        return (StatusCode::OK, "Added vault items").into_response();
+    }
+    let limit_key = scoped_key("put_vault_items", &caller);
+    let limit = app_state.effective_limit(app_state.route_limit("put_vault_items"));
+    let cost = app_state.content_type_cost(&headers);
+    let charged = match app_state.reserve_memory(&limit_key).await {
+        Ok(()) => {
+            app_state
+                .store
+                .inc_by_below_limit(
+                    limit_key.clone(),
+                    limit,
+                    app_state.route_ttl("put_vault_items"),
+                    cost,
+                    app_state.window_mode,
+                )
+                .await
+        },
+        Err(e) => Err(e),
+    };
+    let charged = charged.map(|quota| (quota, Charged { key: limit_key, cost }));
+    quota_response(limit, charged, "Added vault items", app_state.store.now())
+}
+
+/// Rate limit oracle for other services. Callers authenticate with the shared check secret and
+/// always get a 200 carrying the decision, a 429 is reserved for callers of the vault routes.
+pub async fn check_limit(
+    TypedHeader(secret): TypedHeader<Authorization<Bearer>>,
+    State(app_state): State<Arc<AppState>>,
+    Json(check): Json<CheckRequest>,
+) -> Response {
+    if app_state.check_secret.as_deref() != Some(secret.token()) {
+        return RequestError::CheckSecretRequired.into_response();
+    }
+    // unlike the vault routes' limits and windows these come from the caller
+    if !CHECK_TTL_RANGE.contains(&check.ttl) {
+        return bad_request(ModelError::InvalidTtl(check.ttl));
+    }
+    if check.limit < 1 {
+        return bad_request(ModelError::InvalidLimit(check.limit));
+    }
+    let limit_key = scoped_key("check", &check.key);
+    let result = match app_state.reserve_memory(&limit_key).await {
+        Ok(()) => {
+            app_state
+                .store
+                .inc_below_limit(limit_key, check.limit, check.ttl, check.algorithm.window_mode())
+                .await
+        },
+        Err(e) => Err(e),
+    };
+    let (allowed, retry_after) = match result {
+        Ok(_) => (true, None),
+        Err(ModelError::PastRateLimit { retry_after_secs, .. }) => (false, Some(retry_after_secs)),
+        Err(ModelError::LimitExhausted { .. }) => (false, None),
+        Err(e) => return error_response(e),
+    };
+    Json(CheckResponse {
+        allowed,
+        key: check.key,
+        limit: check.limit,
+        algorithm: check.algorithm,
+        retry_after,
+    })
+    .into_response()
+}
+
+/// Scale every route limit at once, e.g. halve them all during a capacity incident. The body is the
+/// new multiplier and the response carries the clamped value that was applied.
+pub async fn put_limit_multiplier(
+    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    State(app_state): State<Arc<AppState>>,
+    multiplier: String,
+) -> Response {
+    if !app_state.is_admin(key.token()) {
+        return RequestError::AdminRequired.into_response();
+    }
+    match multiplier.trim().parse::<f64>() {
+        Ok(multiplier) if multiplier.is_finite() => {
+            (StatusCode::OK, app_state.set_limit_multiplier(multiplier).to_string()).into_response()
+        },
+        _ => RequestError::InvalidMultiplier.into_response(),
+    }
+}
+
+/// Report the caller's remaining quota on every vault route keyed by scope. The caller is found
+/// the same way the routes charge it so anonymous callers see their address's quota. Peeking is
+/// free, it never charges any of the routes.
+pub async fn get_limits(
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(caller) = app_state.caller_id(&headers, connect_info.map(|ConnectInfo(peer)| peer)) else {
+        return RequestError::CallerRequired.into_response();
+    };
+    let mut remaining: HashMap<&str, LimitType> = HashMap::new();
+    for (scope, limit) in &app_state.route_limits {
+        let scope_remaining = app_state
+            .store
+            .remaining(
+                &scoped_key(scope, &caller),
+                app_state.effective_limit(*limit),
+                app_state.route_ttl(scope),
+                app_state.window_mode,
+            )
+            .await;
+        match scope_remaining {
+            Ok(scope_remaining) => remaining.insert(scope.as_str(), scope_remaining),
+            Err(e) => return error_response(e),
+        };
+    }
+    Json(remaining).into_response()
+}
+
+/// One tracked key as listed by `GET /vault/limits/debug`, `expires_in` is in seconds and unset for
+/// keys that never expire
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DebugEntry {
+    pub key: KeyType,
+    pub count: LimitType,
+    pub expires_in: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+/// List every tracked key with its count, time left and tag, sorted by key. The in memory store
+/// reads its published snapshot so this doesn't hold up requests however many keys there are.
+pub async fn get_limits_debug(
+    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    State(app_state): State<Arc<AppState>>,
+) -> Response {
+    if !app_state.is_admin(key.token()) {
+        return RequestError::AdminRequired.into_response();
+    }
+    let now = app_state.store.now();
+    let mut entries: Vec<DebugEntry> = Vec::new();
+    for key in app_state.store.keys().await {
+        let Ok(Some(stored_value)) = app_state.store.get(&key).await else {
+            continue;
+        };
+        entries.push(DebugEntry {
+            key,
+            count: stored_value.count,
+            expires_in: stored_value.ttl.map(|ttl| ttl.signed_duration_since(now).num_seconds()),
+            tag: stored_value.tag,
+        });
+    }
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    Json(entries).into_response()
+}
+
+/// Reset a caller by dropping their bucket, the next request starts a fresh window
+pub async fn delete_limit(
+    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    Path(limit_key): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Response {
+    if !app_state.is_admin(key.token()) {
+        return RequestError::AdminRequired.into_response();
+    }
+    match app_state.store.delete(&limit_key).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Query of `DELETE /vault/limits`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PrefixQuery {
+    pub prefix: String,
+}
+
+/// Body of `DELETE /vault/limits`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeletedKeys {
+    pub deleted: usize,
+}
+
+/// Reset every bucket whose key starts with the prefix at once, e.g. a whole scope after spurious
+/// charges. An empty prefix would wipe the store so it is refused.
+pub async fn delete_limits(
+    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    Query(query): Query<PrefixQuery>,
+    State(app_state): State<Arc<AppState>>,
+) -> Response {
+    if !app_state.is_admin(key.token()) {
+        return RequestError::AdminRequired.into_response();
+    }
+    if query.prefix.is_empty() {
+        return RequestError::EmptyPrefix.into_response();
+    }
+    Json(DeletedKeys {
+        deleted: app_state.store.delete_prefix(&query.prefix).await,
+    })
+    .into_response()
+}
+
+/// Attach the request body as the tag of an existing bucket, an empty body clears the tag. Tags over
+/// MAX_TAG_BYTES are refused with a 400.
+pub async fn put_limit_tag(
+    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    Path(limit_key): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+    tag: String,
+) -> Response {
+    if !app_state.is_admin(key.token()) {
+        return RequestError::AdminRequired.into_response();
+    }
+    let tag = Some(tag).filter(|tag| !tag.is_empty());
+    match app_state.store.set_tag(limit_key, tag).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Refuse every request made with the token from now on, until it is removed again
+pub async fn put_blocklisted_token(
+    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    Path(token): Path<String>,
+    State(app_state): State<Arc<AppState>>,
+) -> Response {
+    if !app_state.is_admin(key.token()) {
+        return RequestError::AdminRequired.into_response();
+    }
+    app_state.blocklisted_tokens.write().insert(token);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Lift a block, the token's existing counters are left as they were
+pub async fn delete_blocklisted_token(
+    TypedHeader(key): TypedHeader<Authorization<Bearer>>,
+    Path(token): Path<String>,
+    State(app_state): State<Arc<AppState>>,
 ) -> Response {
-    if let Err(e) = Store::inc_below_limit(
-        &app_state.store_writer,
-        &app_state.store_reader.handle(),
-        format!("put_vault_items_{}",key.token()),
-        PUT_RATE_LIMIT,
-        app_state.ttl,
-    ) {
-        return (StatusCode::TOO_MANY_REQUESTS, e.to_string()).into_response();
+    if !app_state.is_admin(key.token()) {
+        return RequestError::AdminRequired.into_response();
+    }
+    if app_state.blocklisted_tokens.write().remove(&token) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error_response(ModelError::NotFound)
+    }
+}
+
+/// Prometheus scrape target with the charge tallies, the number of tracked keys and the memory they
+/// are estimated to take
+pub async fn get_metrics(State(app_state): State<Arc<AppState>>) -> Response {
+    let body = app_state
+        .metrics
+        .render(app_state.store.len().await, app_state.store.estimated_memory_bytes().await);
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use axum::body::Body;
+    use rate_limiter_lib::StoredValue;
+    use tower::ServiceExt;
+
+    const TEST_MAX_IN_FLIGHT: u32 = 8;
+
+    pub(crate) async fn test_state() -> Arc<AppState> {
+        Arc::new(test_app_state().await)
+    }
+
+    /// The key's count as the app's store has it, None when the key isn't tracked
+    pub(crate) async fn stored_count(app_state: &AppState, key: KeyType) -> Option<LimitType> {
+        app_state.store.get(&key).await.unwrap().map(|v| v.count)
+    }
+
+    async fn test_app_state() -> AppState {
+        // the reconcile loop is not needed here, tests refresh the store explicitly
+        let (read_handle, write_handle, timer_handler, _) = Store::init().await;
+        timer_handler.abort();
+        let metrics = Arc::new(Metrics::default());
+        AppState {
+            store: Arc::new(MeteredStore::new(Arc::new(MemoryStore::new(read_handle, write_handle)), metrics.clone())),
+            ttl: 60,
+            admin_token: Some("admin".to_string()),
+            check_secret: Some("secret".to_string()),
+            response_bytes_per_unit: 1024,
+            success_only_scopes: HashSet::new(),
+            allowlisted_tokens: HashSet::from(["monitor".to_string()]),
+            blocklisted_tokens: RwLock::new(HashSet::new()),
+            trusted_proxy_count: 0,
+            in_flight: Semaphore::new(TEST_MAX_IN_FLIGHT as usize),
+            limit_multiplier: AtomicU64::new(DEFAULT_LIMIT_MULTIPLIER.to_bits()),
+            content_type_costs: HashMap::from([
+                ("application/json".to_string(), 3),
+                ("multipart/form-data".to_string(), 1),
+            ]),
+            max_memory_bytes: None,
+            memory_policy: MemoryPolicy::Reject,
+            window_mode: WindowMode::Fixed,
+            route_limits: HashMap::from([
+                ("add_vault_item".to_string(), POST_RATE_LIMIT),
+                ("put_vault_items".to_string(), PUT_RATE_LIMIT),
+                ("get_vault_items".to_string(), GET_RATE_LIMIT),
+            ]),
+            route_ttls: HashMap::new(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn bearer_request(method: &str, uri: &str, token: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn handler_throttle_matches_error_response() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        for _ in 0..POST_RATE_LIMIT {
+            let response = app.clone().oneshot(bearer_request("POST", "/vault", "1234")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let throttled = app.oneshot(bearer_request("POST", "/vault", "1234")).await.unwrap();
+        let stored_value = app_state
+            .store
+            .get(&scoped_key("add_vault_item", "1234"))
+            .await
+            .unwrap()
+            .unwrap();
+        let time_remaining = stored_value.ttl.unwrap().signed_duration_since(chrono::Utc::now());
+        // rounded up the same way as the store
+        let time_remaining = (time_remaining.num_milliseconds() + 999) / 1000;
+        let expected = error_response(ModelError::PastRateLimit {
+            retry_after_secs: time_remaining,
+            limit: POST_RATE_LIMIT,
+        });
+        assert_eq!(throttled.status(), expected.status());
+        // the router fills in content-length on the way out so only compare what the builder sets
+        for (name, value) in expected.headers() {
+            assert_eq!(throttled.headers().get(name), Some(value));
+        }
+        assert_eq!(
+            hyper::body::to_bytes(throttled.into_body()).await.unwrap(),
+            hyper::body::to_bytes(expected.into_body()).await.unwrap()
+        );
+    }
+
+    /// Stands in for a backend that has every caller over its limit
+    struct ThrottledStore;
+
+    #[async_trait]
+    impl RateLimiter for ThrottledStore {
+        fn now(&self) -> DateTime<Utc> {
+            Utc::now()
+        }
+
+        async fn inc_below_limit(
+            &self,
+            _: KeyType,
+            limit: LimitType,
+            _: i64,
+            _: WindowMode,
+        ) -> Result<Quota, ModelError> {
+            Err(ModelError::PastRateLimit {
+                retry_after_secs: 42,
+                limit,
+            })
+        }
+
+        async fn inc_by_below_limit(
+            &self,
+            key: KeyType,
+            limit: LimitType,
+            ttl: i64,
+            _: LimitType,
+            mode: WindowMode,
+        ) -> Result<Quota, ModelError> {
+            self.inc_below_limit(key, limit, ttl, mode).await
+        }
+
+        async fn inc_all_below_limit(
+            &self,
+            items: &[(KeyType, LimitType, i64)],
+            _: WindowMode,
+        ) -> Result<Vec<Quota>, ModelError> {
+            let limit = items.first().map(|(_, limit, _)| *limit).unwrap_or_default();
+            Err(ModelError::PastRateLimit {
+                retry_after_secs: 42,
+                limit,
+            })
+        }
+
+        async fn inc_by(&self, _: KeyType, _: LimitType, _: i64, _: WindowMode) -> Result<(), ModelError> {
+            Ok(())
+        }
+
+        async fn refund(&self, _: KeyType, _: LimitType) -> Result<(), ModelError> {
+            Err(ModelError::NotFound)
+        }
+
+        async fn insert(&self, _: &KeyType, _: LimitType, _: i64) -> Result<(), ModelError> {
+            Ok(())
+        }
+
+        async fn set_tag(&self, _: KeyType, _: Option<String>) -> Result<(), ModelError> {
+            Err(ModelError::NotFound)
+        }
+
+        async fn reserve_memory(&self, _: &KeyType, _: usize, _: MemoryPolicy) -> Result<(), ModelError> {
+            Ok(())
+        }
+
+        async fn delete(&self, _: &KeyType) -> Result<(), ModelError> {
+            Err(ModelError::NotFound)
+        }
+
+        async fn delete_prefix(&self, _: &str) -> usize {
+            0
+        }
+
+        async fn get(&self, _: &KeyType) -> Result<Option<StoredValue>, ModelError> {
+            Ok(None)
+        }
+
+        async fn remaining(
+            &self,
+            _: &KeyType,
+            _: LimitType,
+            _: i64,
+            _: WindowMode,
+        ) -> Result<LimitType, ModelError> {
+            Ok(0)
+        }
+
+        async fn keys(&self) -> Vec<KeyType> {
+            Vec::new()
+        }
+
+        async fn len(&self) -> usize {
+            0
+        }
+
+        async fn estimated_memory_bytes(&self) -> usize {
+            0
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_throttle_through_the_store_trait() {
+        let app_state = Arc::new(AppState {
+            store: Arc::new(ThrottledStore),
+            ..test_app_state().await
+        });
+        let app = routes(app_state.clone());
+        for (method, uri) in [("POST", "/vault"), ("PUT", "/vault/1"), ("GET", "/vault/items")] {
+            let response = app.clone().oneshot(bearer_request(method, uri, "mocked")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.headers()[RETRY_AFTER], "42");
+        }
+        let response = app.clone().oneshot(bearer_request("GET", "/vault/limits", "mocked")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let remaining: HashMap<String, LimitType> = serde_json::from_slice(&body).unwrap();
+        assert!(remaining.values().all(|remaining| *remaining == 0));
+        // the in memory store was never touched
+        assert_eq!(app_state.store.len().await, 0);
+
+        // nor is it read by the admin routes and metrics when it does hold a key
+        app_state.store.insert(&scoped_key("add_vault_item", "mocked"), 1, 60).await.unwrap();
+        let response = app.clone().oneshot(bearer_request("GET", "/vault/limits/debug", "admin")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(serde_json::from_slice::<Vec<DebugEntry>>(&body).unwrap().is_empty());
+        let uri = "/vault/limits/add_vault_item:mocked/tag";
+        let response = app.clone().oneshot(bearer_request("PUT", uri, "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.oneshot(bearer_request("GET", "/metrics", "admin")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body).unwrap().contains("rate_limiter_tracked_keys 0"));
+    }
+
+    #[tokio::test]
+    async fn throttled_body_reports_retry_after() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        for _ in 0..POST_RATE_LIMIT {
+            app.clone().oneshot(bearer_request("POST", "/vault", "json")).await.unwrap();
+        }
+        let throttled = app.oneshot(bearer_request("POST", "/vault", "json")).await.unwrap();
+        assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = hyper::body::to_bytes(throttled.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        let stored_value = app_state
+            .store
+            .get(&scoped_key("add_vault_item", "json"))
+            .await
+            .unwrap()
+            .unwrap();
+        let time_remaining = stored_value.ttl.unwrap().signed_duration_since(Utc::now()).num_seconds();
+        assert_eq!(body.error, "rate_limited");
+        assert_eq!(body.limit, Some(POST_RATE_LIMIT));
+        assert_eq!(body.remaining, Some(0));
+        // the second may have ticked over since the response was built
+        assert!((time_remaining..=time_remaining + 1).contains(&body.retry_after_secs.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn exhausted_limit_has_no_retry_after() {
+        let response = error_response(ModelError::LimitExhausted { limit: 5 });
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(!response.headers().contains_key(RETRY_AFTER));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.error, "limit_exhausted");
+        assert_eq!(body.limit, Some(5));
+        assert_eq!(body.remaining, Some(0));
+        assert_eq!(body.retry_after_secs, None);
+    }
+
+    #[tokio::test]
+    async fn responses_carry_rate_limit_headers() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let header = |response: &Response, name: &HeaderName| {
+            response.headers().get(name).map(|value| value.to_str().unwrap().parse::<i64>().unwrap())
+        };
+        let fresh = app.clone().oneshot(bearer_request("POST", "/vault", "headers")).await.unwrap();
+        assert_eq!(fresh.status(), StatusCode::OK);
+        assert_eq!(header(&fresh, &X_RATELIMIT_LIMIT), Some(POST_RATE_LIMIT));
+        assert_eq!(header(&fresh, &X_RATELIMIT_REMAINING), Some(POST_RATE_LIMIT - 1));
+        let reset = app_state
+            .store
+            .get(&scoped_key("add_vault_item", "headers"))
+            .await
+            .unwrap()
+            .and_then(|stored_value| stored_value.ttl)
+            .unwrap()
+            .timestamp();
+        assert_eq!(header(&fresh, &X_RATELIMIT_RESET), Some(reset));
+        assert_eq!(header(&fresh, &RETRY_AFTER), None);
+
+        app.clone().oneshot(bearer_request("POST", "/vault", "headers")).await.unwrap();
+        let near_limit = app.clone().oneshot(bearer_request("POST", "/vault", "headers")).await.unwrap();
+        assert_eq!(near_limit.status(), StatusCode::OK);
+        assert_eq!(header(&near_limit, &X_RATELIMIT_REMAINING), Some(0));
+        assert_eq!(header(&near_limit, &X_RATELIMIT_RESET), Some(reset));
+
+        let over_limit = app.oneshot(bearer_request("POST", "/vault", "headers")).await.unwrap();
+        assert_eq!(over_limit.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&over_limit, &X_RATELIMIT_LIMIT), Some(POST_RATE_LIMIT));
+        assert_eq!(header(&over_limit, &X_RATELIMIT_REMAINING), Some(0));
+        let retry_after = header(&over_limit, &RETRY_AFTER).unwrap();
+        assert!(matches!(retry_after, 59 | 60));
+        assert!((header(&over_limit, &X_RATELIMIT_RESET).unwrap() - reset).abs() <= 1);
+    }
+
+    #[tokio::test]
+    async fn limits_report_remaining_without_charging() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        app.clone().oneshot(bearer_request("POST", "/vault", "peek")).await.unwrap();
+        for _ in 0..3 {
+            let response = app.clone().oneshot(bearer_request("GET", "/vault/limits", "peek")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let remaining: HashMap<String, LimitType> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(remaining["add_vault_item"], POST_RATE_LIMIT - 1);
+            assert_eq!(remaining["put_vault_items"], PUT_RATE_LIMIT);
+            assert_eq!(remaining["get_vault_items"], GET_RATE_LIMIT);
+        }
+        let charged = app_state.store.get(&scoped_key("add_vault_item", "peek")).await.unwrap();
+        assert_eq!(charged.map(|v| v.count), Some(1));
+        assert!(app_state.store.get(&scoped_key("get_vault_items", "peek")).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn limits_are_reported_for_the_caller_the_routes_charge() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let from_address = |method: &str, uri: &str| {
+            let mut request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([203, 0, 113, 9], 4000))));
+            request
+        };
+        app.clone().oneshot(from_address("POST", "/vault")).await.unwrap();
+        let response = app.clone().oneshot(from_address("GET", "/vault/limits")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let remaining: HashMap<String, LimitType> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(remaining["add_vault_item"], POST_RATE_LIMIT - 1);
+
+        // a token can't peek at an address's quota either
+        let response = app.clone().oneshot(bearer_request("GET", "/vault/limits", "ip:203.0.113.9")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let request = Request::builder().uri("/vault/limits").body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn configured_route_limits_replace_the_defaults() {
+        let env: Env = envy::from_iter([
+            ("SERVER_PORT".to_string(), "3000".to_string()),
+            ("TTL".to_string(), "60".to_string()),
+            ("POST_LIMIT".to_string(), "1".to_string()),
+            ("ROUTE_LIMITS".to_string(), "put_vault_items=2,reports=5".to_string()),
+        ])
+        .unwrap();
+        let route_limits = env.route_limits().unwrap();
+        assert_eq!(route_limits["add_vault_item"], 1);
+        assert_eq!(route_limits["put_vault_items"], 2);
+        assert_eq!(route_limits["get_vault_items"], GET_RATE_LIMIT);
+        assert_eq!(route_limits["reports"], 5);
+
+        let mut app_state = test_app_state().await;
+        app_state.route_limits = route_limits;
+        let app = routes(Arc::new(app_state));
+        let response = app.clone().oneshot(bearer_request("POST", "/vault", "configured")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(bearer_request("POST", "/vault", "configured")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn snapshot_schedule_defaults_the_jitter_to_a_tenth_of_the_interval() {
+        let env = |vars: &[(&str, &str)]| {
+            let base = [("SERVER_PORT", "3000"), ("TTL", "60")];
+            envy::from_iter::<_, Env>(base.iter().chain(vars).map(|(k, v)| (k.to_string(), v.to_string()))).unwrap()
+        };
+        assert_eq!(env(&[("SNAPSHOT_PATH", "store.json")]).snapshot_schedule().unwrap(), None);
+        let schedule = env(&[("SNAPSHOT_PATH", "store.json"), ("SNAPSHOT_INTERVAL_SECS", "300")]).snapshot_schedule();
+        assert_eq!(schedule.unwrap(), Some((StdDuration::from_secs(300), StdDuration::from_secs(30))));
+        let schedule = env(&[
+            ("SNAPSHOT_PATH", "store.json"),
+            ("SNAPSHOT_INTERVAL_SECS", "300"),
+            ("SNAPSHOT_JITTER_SECS", "0"),
+        ])
+        .snapshot_schedule();
+        assert_eq!(schedule.unwrap(), Some((StdDuration::from_secs(300), StdDuration::ZERO)));
+        assert!(env(&[("SNAPSHOT_INTERVAL_SECS", "300")]).snapshot_schedule().is_err());
+        assert!(env(&[("SNAPSHOT_PATH", "store.json"), ("SNAPSHOT_INTERVAL_SECS", "0")]).snapshot_schedule().is_err());
+    }
+
+    #[test]
+    fn redis_backend_needs_a_url_and_no_memory_only_settings() {
+        let env = |vars: &[(&str, &str)]| {
+            let base = [("SERVER_PORT", "3000"), ("TTL", "60")];
+            envy::from_iter::<_, Env>(base.iter().chain(vars).map(|(k, v)| (k.to_string(), v.to_string()))).unwrap()
+        };
+        assert_eq!(env(&[("REDIS_URL", "redis://localhost")]).redis_url().unwrap(), None);
+        let redis = [("STORE_BACKEND", "redis"), ("REDIS_URL", "redis://localhost")];
+        assert_eq!(env(&redis).redis_url().unwrap(), Some("redis://localhost"));
+        assert!(env(&[("STORE_BACKEND", "redis")]).redis_url().is_err());
+        let with_snapshots = [redis.as_slice(), &[("SNAPSHOT_PATH", "store.json")]].concat();
+        assert!(env(&with_snapshots).redis_url().is_err());
+    }
+
+    #[test]
+    fn the_cache_only_fronts_the_redis_backend() {
+        let env = |vars: &[(&str, &str)]| {
+            let base = [("SERVER_PORT", "3000"), ("TTL", "60"), ("CACHE_TTL_MS", "50")];
+            envy::from_iter::<_, Env>(base.iter().chain(vars).map(|(k, v)| (k.to_string(), v.to_string()))).unwrap()
+        };
+        assert!(env(&[]).cache().is_err());
+        let cache = env(&[("STORE_BACKEND", "redis"), ("REDIS_URL", "redis://localhost")]).cache();
+        assert_eq!(cache.unwrap(), Some((Duration::milliseconds(50), StdDuration::from_millis(100))));
+    }
+
+    #[tokio::test]
+    async fn configured_route_ttls_set_each_window() {
+        let env: Env = envy::from_iter([
+            ("SERVER_PORT".to_string(), "3000".to_string()),
+            ("TTL".to_string(), "60".to_string()),
+            ("POST_TTL".to_string(), "3600".to_string()),
+            ("ROUTE_TTLS".to_string(), "put_vault_items=10".to_string()),
+        ])
+        .unwrap();
+        let route_ttls = env.route_ttls().unwrap();
+        assert_eq!(route_ttls.get("get_vault_items"), None);
+
+        let mut app_state = test_app_state().await;
+        app_state.route_ttls = route_ttls;
+        let app = routes(Arc::new(app_state));
+        let reset_in = |response: &Response| {
+            let reset = response.headers()[&X_RATELIMIT_RESET].to_str().unwrap().parse::<i64>().unwrap();
+            reset - Utc::now().timestamp()
+        };
+        for (method, uri, ttl) in [("POST", "/vault", 3600), ("PUT", "/vault/1", 10), ("GET", "/vault/items", 60)] {
+            let response = app.clone().oneshot(bearer_request(method, uri, "windows")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!((ttl - 1..=ttl).contains(&reset_in(&response)));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_share_a_combined_limit() {
+        let mut app_state = test_app_state().await;
+        app_state.route_limits.extend([
+            ("vault_reads".to_string(), 5),
+            ("vault_writes".to_string(), 2),
+            ("vault".to_string(), 4),
+        ]);
+        let app_state = Arc::new(app_state);
+        let app = routes(app_state.clone());
+        let status = |method: &'static str, uri: &'static str| {
+            let app = app.clone();
+            async move { app.oneshot(bearer_request(method, uri, "shared")).await.unwrap().status() }
+        };
+        assert_eq!(status("POST", "/vault").await, StatusCode::OK);
+        assert_eq!(status("PUT", "/vault/1").await, StatusCode::OK);
+        // writes are exhausted, which charges neither the writes nor the total
+        assert_eq!(status("POST", "/vault").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status("PUT", "/vault/1").await, StatusCode::TOO_MANY_REQUESTS);
+        // reads still have room until the total is reached
+        assert_eq!(status("GET", "/vault/items").await, StatusCode::OK);
+        let response = app.clone().oneshot(bearer_request("GET", "/vault/items", "shared")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[&X_RATELIMIT_LIMIT], "4");
+        assert_eq!(response.headers()[&X_RATELIMIT_REMAINING], "0");
+        assert_eq!(status("GET", "/vault/items").await, StatusCode::TOO_MANY_REQUESTS);
+
+        let count = |scope: &str| stored_count(&app_state, scoped_key(scope, "shared"));
+        assert_eq!(
+            (count("vault_writes").await, count("vault_reads").await, count("vault").await),
+            (Some(2), Some(2), Some(4))
+        );
+        // the admin routes are not part of the resource
+        assert_eq!(status("GET", "/vault/limits").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn metrics_tally_allowed_and_rejected_requests() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        for _ in 0..POST_RATE_LIMIT + 2 {
+            app.clone().oneshot(bearer_request("POST", "/vault", "metered")).await.unwrap();
+        }
+        let response = app.oneshot(bearer_request("GET", "/metrics", "metered")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let sample = |name: &str| body.lines().find_map(|line| line.strip_prefix(name)).map(str::trim);
+        let expected = POST_RATE_LIMIT.to_string();
+        assert_eq!(sample("rate_limiter_increments_total{scope=\"add_vault_item\"}"), Some(expected.as_str()));
+        assert_eq!(sample("rate_limiter_rejections_total{scope=\"add_vault_item\"}"), Some("2"));
+        assert_eq!(sample("rate_limiter_tracked_keys"), Some("1"));
+        let expected = rate_limiter_lib::ESTIMATED_ENTRY_BYTES.to_string();
+        assert_eq!(sample("rate_limiter_estimated_memory_bytes"), Some(expected.as_str()));
+    }
+
+    #[tokio::test]
+    async fn allowlisted_tokens_are_never_limited() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        for (method, uri, limit) in [("POST", "/vault", POST_RATE_LIMIT), ("PUT", "/vault/1", PUT_RATE_LIMIT)] {
+            for _ in 0..limit + 2 {
+                let response = app.clone().oneshot(bearer_request(method, uri, "monitor")).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+        }
+        for _ in 0..POST_RATE_LIMIT {
+            app.clone().oneshot(bearer_request("POST", "/vault", "unlisted")).await.unwrap();
+        }
+        let response = app.oneshot(bearer_request("POST", "/vault", "unlisted")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // nothing was tracked for the allowlisted token
+        assert!(app_state.store.get(&scoped_key("add_vault_item", "monitor")).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn blocklisted_tokens_are_refused_until_removed() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let response = app.clone().oneshot(bearer_request("PUT", "/vault/blocklist/abuser", "abuser")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(bearer_request("PUT", "/vault/blocklist/abuser", "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // refused on every route even though nothing has been charged yet
+        for (method, uri) in [("POST", "/vault"), ("PUT", "/vault/1"), ("GET", "/vault/items")] {
+            let response = app.clone().oneshot(bearer_request(method, uri, "abuser")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.error, "blocked");
+        }
+        assert_eq!(app_state.store.len().await, 0);
+
+        let response = app.clone().oneshot(bearer_request("DELETE", "/vault/blocklist/abuser", "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.clone().oneshot(bearer_request("DELETE", "/vault/blocklist/abuser", "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.oneshot(bearer_request("POST", "/vault", "abuser")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn blocklist_takes_precedence_over_allowlist() {
+        let app_state = test_state().await;
+        app_state.blocklisted_tokens.write().insert("monitor".to_string());
+        let app = routes(app_state.clone());
+        for (method, uri) in [("POST", "/vault"), ("PUT", "/vault/1"), ("GET", "/vault/items")] {
+            let response = app.clone().oneshot(bearer_request(method, uri, "monitor")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn anonymous_callers_are_limited_by_address() {
+        let mut app_state = test_app_state().await;
+        app_state.trusted_proxy_count = 1;
+        let app_state = Arc::new(app_state);
+        let app = routes(app_state.clone());
+        let from = |token: Option<&str>| {
+            let mut request = Request::builder()
+                .method("POST")
+                .uri("/vault")
+                .header("X-Forwarded-For", "203.0.113.9");
+            if let Some(token) = token {
+                request = request.header("Authorization", format!("Bearer {}", token));
+            }
+            let mut request = request.body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+            request
+        };
+        let count = |caller: &str| stored_count(&app_state, scoped_key("add_vault_item", caller));
+
+        // a token takes precedence over the address
+        let response = app.clone().oneshot(from(Some("anonymous-token"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(count("anonymous-token").await, Some(1));
+        assert_eq!(count("ip:203.0.113.9").await, None);
+
+        for _ in 0..POST_RATE_LIMIT {
+            let response = app.clone().oneshot(from(None)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app.clone().oneshot(from(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(count("ip:203.0.113.9").await, Some(POST_RATE_LIMIT));
+        assert_eq!(count("anonymous-token").await, Some(1));
+
+        // neither a token nor a connection to take the address from
+        let request = Request::builder().method("POST").uri("/vault").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn tokens_cannot_claim_an_address_bucket() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let from_address = || {
+            let mut request = Request::builder().method("POST").uri("/vault").body(Body::empty()).unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([203, 0, 113, 9], 4000))));
+            request
+        };
+        let response = app.clone().oneshot(from_address()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for (method, uri) in [("POST", "/vault"), ("PUT", "/vault/1"), ("GET", "/vault/items")] {
+            let response = app.clone().oneshot(bearer_request(method, uri, "ip:203.0.113.9")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        // the address's bucket was neither charged by nor shared with the token
+        let count = app_state.store.get(&scoped_key("add_vault_item", "ip:203.0.113.9")).await.unwrap();
+        assert_eq!(count.map(|v| v.count), Some(1));
+        assert_eq!(app_state.store.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn admin_can_reset_a_caller() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        for _ in 0..POST_RATE_LIMIT {
+            app.clone().oneshot(bearer_request("POST", "/vault", "blocked")).await.unwrap();
+        }
+        let response = app.clone().oneshot(bearer_request("POST", "/vault", "blocked")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let uri = "/vault/limits/add_vault_item:blocked";
+        let response = app.clone().oneshot(bearer_request("DELETE", uri, "blocked")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(bearer_request("DELETE", uri, "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.clone().oneshot(bearer_request("DELETE", uri, "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: ErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.error, "not_found");
+        assert_eq!(body.retry_after_secs, None);
+
+        let response = app.oneshot(bearer_request("POST", "/vault", "blocked")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_can_reset_a_prefix() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        for token in ["a", "b"] {
+            for _ in 0..POST_RATE_LIMIT {
+                app.clone().oneshot(bearer_request("POST", "/vault", token)).await.unwrap();
+            }
+        }
+        app.clone().oneshot(bearer_request("PUT", "/vault/1", "a")).await.unwrap();
+        let deleted = |response: Response| async move {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<DeletedKeys>(&body).unwrap().deleted
+        };
+
+        let uri = "/vault/limits?prefix=add_vault_item:";
+        let response = app.clone().oneshot(bearer_request("DELETE", uri, "a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(bearer_request("DELETE", uri, "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(deleted(response).await, 2);
+        let response = app.clone().oneshot(bearer_request("DELETE", uri, "admin")).await.unwrap();
+        assert_eq!(deleted(response).await, 0);
+        let response = app.clone().oneshot(bearer_request("DELETE", "/vault/limits?prefix=", "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(app_state.store.keys().await, vec![scoped_key("put_vault_items", "a")]);
+        let response = app.oneshot(bearer_request("POST", "/vault", "a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn debug_listing_reports_counts_and_ttls() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        app_state.store.insert(&"get_vault_items:b".to_string(), 7, 30).await.unwrap();
+        for _ in 0..2 {
+            app.clone().oneshot(bearer_request("POST", "/vault", "a")).await.unwrap();
+        }
+        let response = app.clone().oneshot(bearer_request("GET", "/vault/limits/debug", "a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.oneshot(bearer_request("GET", "/vault/limits/debug", "admin")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let entries: Vec<DebugEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries.iter().map(|e| (e.key.as_str(), e.count)).collect::<Vec<_>>(), vec![
+            ("add_vault_item:a", 2),
+            ("get_vault_items:b", 7)
+        ]);
+        // whole seconds left, the window may have started just before a second ticked over
+        assert!(matches!(entries[0].expires_in, Some(59 | 60)));
+        assert!(matches!(entries[1].expires_in, Some(29 | 30)));
+    }
+
+    #[tokio::test]
+    async fn tagged_buckets_show_up_in_the_debug_listing() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let tag = |token: &str, tag: String| {
+            Request::builder()
+                .method("PUT")
+                .uri("/vault/limits/add_vault_item:tagged/tag")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(tag))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(tag("admin", "vip".to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        app.clone().oneshot(bearer_request("POST", "/vault", "tagged")).await.unwrap();
+        let response = app.clone().oneshot(tag("tagged", "vip".to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(tag("admin", "vip".to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let too_long = "v".repeat(rate_limiter_lib::MAX_TAG_BYTES + 1);
+        let response = app.clone().oneshot(tag("admin", too_long)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<ErrorBody>(&body).unwrap().error, "tag_too_long");
+        app.clone().oneshot(bearer_request("POST", "/vault", "tagged")).await.unwrap();
+
+        let response = app.oneshot(bearer_request("GET", "/vault/limits/debug", "admin")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let entries: Vec<DebugEntry> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].count, 2);
+        assert_eq!(entries[0].tag.as_deref(), Some("vip"));
+    }
+
+    #[tokio::test]
+    async fn check_reports_throttle_decision() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let check = |secret: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/check")
+                .header("Authorization", format!("Bearer {}", secret))
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{"key": "tenant-a", "limit": 2, "ttl": 30}"#))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(check("wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let mut decisions = Vec::new();
+        for _ in 0..4 {
+            let response = app.clone().oneshot(check("secret")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            decisions.push(serde_json::from_slice::<CheckResponse>(&body).unwrap());
+        }
+        assert!(decisions[0].allowed && decisions[1].allowed);
+        for decision in &decisions[2..] {
+            assert!(!decision.allowed);
+            assert_eq!(decision.limit, 2);
+            assert_eq!(decision.algorithm, CheckAlgorithm::FixedWindow);
+            assert!(matches!(decision.retry_after, Some(29 | 30)));
+        }
+    }
+
+    #[tokio::test]
+    async fn check_rejects_out_of_range_input() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let check = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri("/check")
+                .header("Authorization", "Bearer secret")
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        for (body, error) in [
+            // large enough to overflow the window's expiry
+            (r#"{"key": "tenant-a", "limit": 2, "ttl": 9223372036854775807}"#, "invalid_ttl"),
+            (r#"{"key": "tenant-a", "limit": 2, "ttl": 0}"#, "invalid_ttl"),
+            (r#"{"key": "tenant-a", "limit": 2, "ttl": 86401}"#, "invalid_ttl"),
+            (r#"{"key": "tenant-a", "limit": 0, "ttl": 30}"#, "invalid_limit"),
+            (r#"{"key": "tenant-a", "limit": -1, "ttl": 30}"#, "invalid_limit"),
+        ] {
+            let response = app.clone().oneshot(check(body)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(serde_json::from_slice::<ErrorBody>(&body).unwrap().error, error);
+        }
+        assert_eq!(app_state.store.len().await, 0);
+        let response = app.oneshot(check(r#"{"key": "tenant-a", "limit": 1, "ttl": 86400}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn refused_requests_get_a_json_error_body() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let with_body = |method: &str, uri: &str, token: &str, body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let anonymous = Request::builder().uri("/vault/items").body(Body::empty()).unwrap();
+        let check = with_body("POST", "/check", "wrong", r#"{"key": "tenant-a", "limit": 2, "ttl": 30}"#);
+        for (request, status, error) in [
+            (anonymous, StatusCode::UNAUTHORIZED, "caller_required"),
+            (bearer_request("DELETE", "/vault/limits?prefix=a", "a"), StatusCode::UNAUTHORIZED, "admin_required"),
+            (check, StatusCode::UNAUTHORIZED, "check_secret_required"),
+            (bearer_request("DELETE", "/vault/limits?prefix=", "admin"), StatusCode::BAD_REQUEST, "empty_prefix"),
+            (
+                with_body("PUT", "/vault/limits/multiplier", "admin", "lots"),
+                StatusCode::BAD_REQUEST,
+                "invalid_multiplier",
+            ),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(serde_json::from_slice::<ErrorBody>(&body).unwrap().error, error);
+        }
+        let _held = app_state.in_flight.acquire_many(TEST_MAX_IN_FLIGHT).await.unwrap();
+        let response = app.oneshot(bearer_request("GET", "/vault/items", "fresh")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(serde_json::from_slice::<ErrorBody>(&body).unwrap().error, "at_capacity");
+    }
+
+    #[tokio::test]
+    async fn limit_multiplier_scales_every_route() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let request = Request::builder()
+            .method("PUT")
+            .uri("/vault/limits/multiplier")
+            .header("Authorization", "Bearer admin")
+            .body(Body::from("0.5"))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(app_state.effective_limit(GET_RATE_LIMIT), 600);
+        assert_eq!(app_state.effective_limit(PUT_RATE_LIMIT), 30);
+        assert_eq!(app_state.effective_limit(POST_RATE_LIMIT), 1);
+
+        let response = app.clone().oneshot(bearer_request("POST", "/vault", "1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(bearer_request("POST", "/vault", "1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(app_state.set_limit_multiplier(0.0), 0.1);
+        // scaled down limits keep at least one request
+        assert_eq!(app_state.effective_limit(POST_RATE_LIMIT), 1);
+        assert_eq!(app_state.set_limit_multiplier(1000.0), 10.0);
+    }
+
+    #[tokio::test]
+    async fn content_type_sets_body_cost() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        for (token, content_type, cost) in [
+            ("json", "application/json", 3),
+            ("multipart", "multipart/form-data; boundary=X", 1),
+            ("plain", "text/plain", 1),
+        ] {
+            let request = Request::builder()
+                .method("PUT")
+                .uri("/vault/1")
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", content_type)
+                .body(Body::from("same sized body"))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let key = scoped_key("put_vault_items", token);
+            assert_eq!(stored_count(&app_state, key.clone()).await, Some(cost));
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_budget_rejects_new_callers() {
+        let app_state = Arc::new(AppState {
+            max_memory_bytes: Some(2 * rate_limiter_lib::ESTIMATED_ENTRY_BYTES),
+            ..test_app_state().await
+        });
+        let app = routes(app_state.clone());
+        for token in ["a", "b"] {
+            let response = app.clone().oneshot(bearer_request("GET", "/vault/items", token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app.clone().oneshot(bearer_request("GET", "/vault/items", "c")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = app.oneshot(bearer_request("GET", "/vault/items", "a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn saturated_server_sheds_load() {
+        let app_state = test_state().await;
+        let app = routes(app_state.clone());
+        let held = app_state.in_flight.acquire_many(TEST_MAX_IN_FLIGHT).await.unwrap();
+        let response = app.clone().oneshot(bearer_request("GET", "/vault/items", "fresh")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(held);
+        let response = app.oneshot(bearer_request("GET", "/vault/items", "fresh")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn failed_requests_do_not_consume_quota() {
+        let app_state = test_state().await;
+        let handler_state = app_state.clone();
+        let app = Router::new().route(
+            "/flaky/:status",
+            get(move |TypedHeader(key): TypedHeader<Authorization<Bearer>>, Path(status): Path<u16>| async move {
+                let key = scoped_key("flaky", key.token());
+                let charged = handler_state
+                    .store
+                    .inc_below_limit(key.clone(), 10, handler_state.ttl, handler_state.window_mode)
+                    .await;
+                if let Err(e) = charged {
+                    return error_response(e);
+                }
+                (StatusCode::from_u16(status).unwrap(), Extension(Charged { key, cost: 1 })).into_response()
+            })
+            .route_layer(middleware::from_fn_with_state(app_state.clone(), release_on_failure)),
+        );
+        let key = scoped_key("flaky", "1234");
+
+        app.clone().oneshot(bearer_request("GET", "/flaky/200", "1234")).await.unwrap();
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(1));
+
+        let response = app.clone().oneshot(bearer_request("GET", "/flaky/500", "1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(1));
+
+        app.oneshot(bearer_request("GET", "/flaky/200", "1234")).await.unwrap();
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn failed_requests_refund_what_they_were_charged() {
+        let mut app_state = test_app_state().await;
+        app_state.success_only_scopes = HashSet::from(["put_vault_items".to_string()]);
+        let app_state = Arc::new(app_state);
+        let key = scoped_key("put_vault_items", "json");
+        let json_put = |uri: &str| {
+            Request::builder()
+                .method("PUT")
+                .uri(uri)
+                .header("Authorization", "Bearer json")
+                .header("Content-Type", "application/json")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = routes(app_state.clone()).oneshot(json_put("/vault/1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(3));
+
+        // the same route, except the work fails after the charge or before it is made
+        let handler_state = app_state.clone();
+        let app = charge_on_success(
+            put(move |headers: HeaderMap, Path(fail_after_charge): Path<bool>| async move {
+                if !fail_after_charge {
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+                let response = put_vault_items(None, Path("1".to_string()), State(handler_state), headers).await;
+                (StatusCode::INTERNAL_SERVER_ERROR, response).into_response()
+            }),
+            &app_state,
+            "put_vault_items",
+        );
+        let app = Router::new().route("/failing/:fail_after_charge", app).with_state(app_state.clone());
+        let response = app.clone().oneshot(json_put("/failing/true")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(3));
+        let response = app.oneshot(json_put("/failing/false")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn response_bytes_are_charged_proportionally() {
+        let app_state = test_state().await;
+        let app = Router::new().route(
+            "/download/:size",
+            get(|Path(size): Path<usize>| async move { vec![0_u8; size] })
+                .route_layer(middleware::from_fn_with_state((app_state.clone(), "download"), charge_response_bytes)),
+        );
+        let key = scoped_key("download", "1234");
+
+        let response = app.clone().oneshot(bearer_request("GET", "/download/10240", "1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(10));
+
+        app.oneshot(bearer_request("GET", "/download/5000", "1234")).await.unwrap();
+        assert_eq!(stored_count(&app_state, key.clone()).await, Some(14));
+    }
+
+    #[tokio::test]
+    async fn error_responses_are_not_charged_for_bytes() {
+        let app_state = test_state().await;
+        let app = Router::new().route(
+            "/download",
+            get(|| async { (StatusCode::NOT_FOUND, vec![0_u8; 10240]) })
+                .route_layer(middleware::from_fn_with_state((app_state.clone(), "download"), charge_response_bytes)),
+        );
+        let response = app.oneshot(bearer_request("GET", "/download", "1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let key = scoped_key("download", "1234");
+        assert!(app_state.store.get(&key).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn response_bytes_respect_the_memory_budget() {
+        let app_state = Arc::new(AppState {
+            max_memory_bytes: Some(0),
+            ..test_app_state().await
+        });
+        let app = Router::new().route(
+            "/download",
+            get(|| async { vec![0_u8; 10240] })
+                .route_layer(middleware::from_fn_with_state((app_state.clone(), "download"), charge_response_bytes)),
+        );
+        let response = app.oneshot(bearer_request("GET", "/download", "1234")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let key = scoped_key("download", "1234");
+        assert!(app_state.store.get(&key).await.unwrap().is_none());
     }
-    (StatusCode::OK, "Added vault items").into_response()
 }
@@ -0,0 +1,196 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rate_limiter_lib::{
+    KeyType,
+    LimitType,
+    MemoryPolicy,
+    ModelError,
+    Quota,
+    RateLimiter,
+    StoredValue,
+    WindowMode,
+    key_scope,
+};
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+/// Scope reported for keys that weren't built by scoped_key
+const UNSCOPED: &str = "unscoped";
+
+/// Allowed and rejected charge counts per scope, rendered in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    /// (increments, rejections) keyed by scope, ordered so the output is stable between scrapes
+    tallies: Mutex<BTreeMap<String, (u64, u64)>>,
+}
+
+impl Metrics {
+    /// Count the outcome of a charge against `key`. Only requests that were throttled count as
+    /// rejections, errors such as an invalid cost are neither.
+    pub fn record(&self, key: &str, result: Result<&Quota, &ModelError>) {
+        let scope = key_scope(key).unwrap_or(UNSCOPED);
+        let mut tallies = self.tallies.lock();
+        match result {
+            Ok(_) => tallies.entry(scope.to_string()).or_default().0 += 1,
+            Err(ModelError::PastRateLimit { .. } | ModelError::LimitExhausted { .. }) => {
+                tallies.entry(scope.to_string()).or_default().1 += 1
+            },
+            Err(_) => {},
+        }
+    }
+
+    /// Render every counter along with the number of keys the store is tracking and its estimated
+    /// memory use
+    pub fn render(&self, tracked_keys: usize, estimated_memory_bytes: usize) -> String {
+        let tallies = self.tallies.lock();
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP rate_limiter_increments_total Requests charged against a limit.");
+        let _ = writeln!(out, "# TYPE rate_limiter_increments_total counter");
+        for (scope, (increments, _)) in tallies.iter() {
+            let _ = writeln!(out, "rate_limiter_increments_total{{scope=\"{}\"}} {}", escape(scope), increments);
+        }
+        let _ = writeln!(out, "# HELP rate_limiter_rejections_total Requests rejected for being past a limit.");
+        let _ = writeln!(out, "# TYPE rate_limiter_rejections_total counter");
+        for (scope, (_, rejections)) in tallies.iter() {
+            let _ = writeln!(out, "rate_limiter_rejections_total{{scope=\"{}\"}} {}", escape(scope), rejections);
+        }
+        let _ = writeln!(out, "# HELP rate_limiter_tracked_keys Keys currently held by the store.");
+        let _ = writeln!(out, "# TYPE rate_limiter_tracked_keys gauge");
+        let _ = writeln!(out, "rate_limiter_tracked_keys {}", tracked_keys);
+        let _ = writeln!(out, "# HELP rate_limiter_estimated_memory_bytes Approximate bytes held by the store.");
+        let _ = writeln!(out, "# TYPE rate_limiter_estimated_memory_bytes gauge");
+        let _ = writeln!(out, "rate_limiter_estimated_memory_bytes {}", estimated_memory_bytes);
+        out
+    }
+}
+
+/// Label values may not contain a raw backslash, quote or newline
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Wraps a store so every charge made through it is counted in `metrics`
+pub struct MeteredStore {
+    inner: Arc<dyn RateLimiter + Send + Sync>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredStore {
+    pub fn new(inner: Arc<dyn RateLimiter + Send + Sync>, metrics: Arc<Metrics>) -> Self {
+        MeteredStore { inner, metrics }
+    }
+}
+
+#[async_trait]
+impl RateLimiter for MeteredStore {
+    fn now(&self) -> DateTime<Utc> {
+        self.inner.now()
+    }
+
+    async fn inc_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError> {
+        let result = self.inner.inc_below_limit(key.clone(), limit, ttl, mode).await;
+        self.metrics.record(&key, result.as_ref());
+        result
+    }
+
+    async fn inc_by_below_limit(
+        &self,
+        key: KeyType,
+        limit: LimitType,
+        ttl: i64,
+        cost: LimitType,
+        mode: WindowMode,
+    ) -> Result<Quota, ModelError> {
+        let result = self.inner.inc_by_below_limit(key.clone(), limit, ttl, cost, mode).await;
+        self.metrics.record(&key, result.as_ref());
+        result
+    }
+
+    /// Every key of an allowed batch counts as charged, a rejected batch counts once against the
+    /// first key since the error doesn't say which of the limits was reached
+    async fn inc_all_below_limit(
+        &self,
+        items: &[(KeyType, LimitType, i64)],
+        mode: WindowMode,
+    ) -> Result<Vec<Quota>, ModelError> {
+        let result = self.inner.inc_all_below_limit(items, mode).await;
+        match &result {
+            Ok(quotas) => {
+                for ((key, ..), quota) in items.iter().zip(quotas) {
+                    self.metrics.record(key, Ok(quota));
+                }
+            },
+            Err(e) => {
+                if let Some((key, ..)) = items.first() {
+                    self.metrics.record(key, Err(e));
+                }
+            },
+        }
+        result
+    }
+
+    async fn inc_by(&self, key: KeyType, amount: LimitType, ttl: i64, mode: WindowMode) -> Result<(), ModelError> {
+        self.inner.inc_by(key, amount, ttl, mode).await
+    }
+
+    async fn refund(&self, key: KeyType, amount: LimitType) -> Result<(), ModelError> {
+        self.inner.refund(key, amount).await
+    }
+
+    async fn insert(&self, key: &KeyType, count: LimitType, ttl: i64) -> Result<(), ModelError> {
+        self.inner.insert(key, count, ttl).await
+    }
+
+    async fn set_tag(&self, key: KeyType, tag: Option<String>) -> Result<(), ModelError> {
+        self.inner.set_tag(key, tag).await
+    }
+
+    async fn reserve_memory(
+        &self,
+        key: &KeyType,
+        max_memory_bytes: usize,
+        policy: MemoryPolicy,
+    ) -> Result<(), ModelError> {
+        self.inner.reserve_memory(key, max_memory_bytes, policy).await
+    }
+
+    async fn delete(&self, key: &KeyType) -> Result<(), ModelError> {
+        self.inner.delete(key).await
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> usize {
+        self.inner.delete_prefix(prefix).await
+    }
+
+    async fn get(&self, key: &KeyType) -> Result<Option<StoredValue>, ModelError> {
+        self.inner.get(key).await
+    }
+
+    async fn remaining(
+        &self,
+        key: &KeyType,
+        limit: LimitType,
+        ttl: i64,
+        mode: WindowMode,
+    ) -> Result<LimitType, ModelError> {
+        self.inner.remaining(key, limit, ttl, mode).await
+    }
+
+    async fn keys(&self) -> Vec<KeyType> {
+        self.inner.keys().await
+    }
+
+    async fn len(&self) -> usize {
+        self.inner.len().await
+    }
+
+    async fn estimated_memory_bytes(&self) -> usize {
+        self.inner.estimated_memory_bytes().await
+    }
+}